@@ -0,0 +1,90 @@
+use crate::mapper::MapperBase;
+use crate::rom::Region;
+#[cfg(feature = "sdl")]
+use sdl2::audio::AudioQueue;
+#[cfg(feature = "sdl")]
+use sdl2::audio::AudioSpecDesired;
+#[cfg(feature = "sdl")]
+use sdl2::AudioSubsystem;
+
+pub const SAMPLE_RATE: i32 = 44100;
+const MAX_QUEUED_BYTES: u32 = (SAMPLE_RATE as u32 / 5) * 2;
+const EXSOUND_GAIN: f32 = 300.0;
+
+pub struct Audio {
+    #[cfg(feature = "sdl")]
+    queue: Option<AudioQueue<i16>>,
+    cycles: f64,
+    cycles_per_sample: f64,
+    buffer: Vec<i16>,
+    // Samples kept for a recording until it takes them each frame.
+    pub captured: Option<Vec<i16>>,
+
+    // Expansion audio goes through the cartridge's RC low-pass and the
+    // console's AC coupling before it reaches the output.
+    lowpass: f32,
+    highpass_in: f32,
+    highpass_out: f32,
+}
+impl Audio {
+    // Silent until `open` gives it a device; the samples are still made for
+    // recordings.
+    pub fn new(region: Region) -> Self {
+        Self {
+            #[cfg(feature = "sdl")]
+            queue: None,
+            cycles: 0.0,
+            cycles_per_sample: region.cpu_clock() / SAMPLE_RATE as f64,
+            buffer: Vec::with_capacity(1024),
+            captured: None,
+            lowpass: 0.0,
+            highpass_in: 0.0,
+            highpass_out: 0.0,
+        }
+    }
+    #[cfg(feature = "sdl")]
+    pub fn open(&mut self, subsystem: AudioSubsystem) {
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        match subsystem.open_queue::<i16, _>(None, &desired) {
+            Result::Ok(queue) => {
+                queue.resume();
+                self.queue = Some(queue);
+            }
+            Result::Err(err) => eprintln!("Cannot open audio device: {}", err),
+        }
+    }
+    pub fn run(&mut self, cpuclock: usize, mapper: &mut Box<dyn MapperBase>) {
+        self.cycles += cpuclock as f64;
+        while self.cycles >= self.cycles_per_sample {
+            self.cycles -= self.cycles_per_sample;
+            let level = mapper.out_exsound() as f32 * EXSOUND_GAIN;
+            let sample = self.filter(level);
+            self.buffer.push(sample);
+            if let Some(captured) = self.captured.as_mut() {
+                captured.push(sample);
+            }
+        }
+        if self.buffer.len() >= 512 {
+            self.flush();
+        }
+    }
+    fn filter(&mut self, level: f32) -> i16 {
+        self.lowpass += (level - self.lowpass) * 0.25;
+        self.highpass_out = 0.996 * (self.highpass_out + self.lowpass - self.highpass_in);
+        self.highpass_in = self.lowpass;
+        return self.highpass_out.max(-32768.0).min(32767.0) as i16;
+    }
+    fn flush(&mut self) {
+        #[cfg(feature = "sdl")]
+        if let Some(queue) = &self.queue {
+            if queue.size() < MAX_QUEUED_BYTES {
+                let _ = queue.queue_audio(&self.buffer);
+            }
+        }
+        self.buffer.clear();
+    }
+}
@@ -0,0 +1,200 @@
+use crate::crt::Mask;
+use crate::palette::NtscSettings;
+use crate::rom::Region;
+use std::fs;
+
+const CONFIG_FILE: &str = "famicom.cfg";
+
+// Frontend settings. Apart from the region override, everything here only
+// affects how frames are shown; the emulated machine always produces the
+// full 256x240 picture.
+pub struct Overscan {
+    pub top: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub right: u32,
+}
+
+pub struct Config {
+    pub filename: String,
+    pub overscan: Overscan,
+    pub region: Option<Region>,
+    pub palette: String,
+    pub filter: String,
+    pub sprite_limit: bool,
+    pub map: String,
+    pub hd_pack: String,
+    pub screenshot_indexed: bool,
+    pub record: String,
+    pub gif_seconds: u32,
+    pub frontend: String,
+    pub crt: f32,
+    pub crt_mask: Mask,
+    pub ntsc: NtscSettings,
+}
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            filename: "sm.nes".to_string(),
+            overscan: Overscan {
+                top: 8,
+                bottom: 8,
+                left: 0,
+                right: 0,
+            },
+            region: None,
+            palette: "default".to_string(),
+            filter: "none".to_string(),
+            sprite_limit: true,
+            map: String::new(),
+            hd_pack: String::new(),
+            screenshot_indexed: false,
+            record: String::new(),
+            gif_seconds: 10,
+            frontend: "sdl".to_string(),
+            crt: 0.0,
+            crt_mask: Mask::GRILLE,
+            ntsc: NtscSettings::new(),
+        }
+    }
+    // Settings come from famicom.cfg (or the file named by --config) first,
+    // then the command line.
+    pub fn from_args(args: &[String]) -> Self {
+        let mut config = Config::new();
+        let path = match args.iter().position(|arg| arg == "--config") {
+            Some(i) if i + 1 < args.len() => args[i + 1].as_str(),
+            _ => CONFIG_FILE,
+        };
+        config.load_file(path);
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            if !arg.starts_with("--") {
+                config.filename = arg.to_string();
+                i += 1;
+                continue;
+            }
+            let value = match args.get(i + 1) {
+                Some(value) => value.as_str(),
+                None => {
+                    eprintln!("Missing value for {}", arg);
+                    break;
+                }
+            };
+            config.set(&arg[2..], value);
+            i += 2;
+        }
+        return config;
+    }
+    // One `key = value` per line, using the command line option names.
+    pub fn load_file(&mut self, path: &str) {
+        let text = match fs::read_to_string(path) {
+            Result::Ok(text) => text,
+            Result::Err(_) => return,
+        };
+        println!("loading config from {}", path);
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => eprintln!("Invalid config line: {}", line),
+            }
+        }
+    }
+    pub fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "config" => {}
+            "overscan" => {
+                let v = parse_pixels(key, value, 120);
+                self.overscan.top = v;
+                self.overscan.bottom = v;
+                self.overscan.left = v;
+                self.overscan.right = v;
+            }
+            "overscan-top" => self.overscan.top = parse_pixels(key, value, 120),
+            "overscan-bottom" => self.overscan.bottom = parse_pixels(key, value, 120),
+            "overscan-left" => self.overscan.left = parse_pixels(key, value, 128),
+            "overscan-right" => self.overscan.right = parse_pixels(key, value, 128),
+            "region" => match value {
+                "auto" => self.region = None,
+                _ => match Region::from_name(value) {
+                    Some(region) => self.region = Some(region),
+                    None => eprintln!("Invalid value for --{}: {}", key, value),
+                },
+            },
+            "palette" => self.palette = value.to_string(),
+            "filter" => self.filter = value.to_string(),
+            "hd-pack" => self.hd_pack = value.to_string(),
+            "screenshot-indexed" => {
+                self.screenshot_indexed = parse_switch(key, value, self.screenshot_indexed)
+            }
+            "record" => self.record = value.to_string(),
+            "gif-seconds" => self.gif_seconds = parse_number(key, value, self.gif_seconds),
+            "frontend" => self.frontend = value.to_string(),
+            "map" => self.map = value.to_string(),
+            "crt" => self.crt = parse_float(key, value, self.crt),
+            "crt-mask" => match Mask::from_name(value) {
+                Some(mask) => self.crt_mask = mask,
+                None => eprintln!("Invalid value for --{}: {}", key, value),
+            },
+            "sprite-limit" => self.sprite_limit = parse_switch(key, value, self.sprite_limit),
+            "hue" => self.ntsc.hue = parse_float(key, value, self.ntsc.hue),
+            "saturation" => self.ntsc.saturation = parse_float(key, value, self.ntsc.saturation),
+            "contrast" => self.ntsc.contrast = parse_float(key, value, self.ntsc.contrast),
+            "brightness" => self.ntsc.brightness = parse_float(key, value, self.ntsc.brightness),
+            "gamma" => self.ntsc.gamma = parse_float(key, value, self.ntsc.gamma),
+            _ => eprintln!("Unknown option: --{}", key),
+        }
+    }
+    pub fn width(&self) -> u32 {
+        return 256 - self.overscan.left - self.overscan.right;
+    }
+    pub fn height(&self) -> u32 {
+        return 240 - self.overscan.top - self.overscan.bottom;
+    }
+}
+
+fn parse_pixels(key: &str, value: &str, max: u32) -> u32 {
+    match value.parse::<u32>() {
+        Result::Ok(v) if v < max => v,
+        _ => {
+            eprintln!("Invalid value for --{}: {}", key, value);
+            0
+        }
+    }
+}
+
+fn parse_number(key: &str, value: &str, default: u32) -> u32 {
+    match value.parse::<u32>() {
+        Result::Ok(v) => v,
+        Result::Err(_) => {
+            eprintln!("Invalid value for --{}: {}", key, value);
+            default
+        }
+    }
+}
+
+fn parse_float(key: &str, value: &str, default: f32) -> f32 {
+    match value.parse::<f32>() {
+        Result::Ok(v) => v,
+        Result::Err(_) => {
+            eprintln!("Invalid value for --{}: {}", key, value);
+            default
+        }
+    }
+}
+
+fn parse_switch(key: &str, value: &str, default: bool) -> bool {
+    match value {
+        "on" | "true" | "1" => true,
+        "off" | "false" | "0" => false,
+        _ => {
+            eprintln!("Invalid value for --{}: {}", key, value);
+            default
+        }
+    }
+}
@@ -121,6 +121,7 @@ impl Cpu {
         let admstr = opobj.adm.to_string();
         let op = opobj.op.to_string();
         let adrm = self.get_addr(admstr.as_str());
+        self.mem.clock = self.cpuclock as usize;
 
         if test {
             self.show_test_state(pc, &op, &admstr);
@@ -681,17 +682,17 @@ impl Cpu {
             "IRQ" => {
                 let pushpc = self.pc;
 
-                let sp = self.post_incsp();
+                let sp = self.post_decsp();
                 let adr = 0x100 + sp as u16;
                 let data = pushpc >> 8;
                 self.mem.set(adr, data as u8);
 
-                let sp = self.post_incsp();
+                let sp = self.post_decsp();
                 let adr = 0x100 + sp as u16;
                 let data = pushpc & 0xff;
                 self.mem.set(adr, data as u8);
 
-                let sp = self.post_incsp();
+                let sp = self.post_decsp();
                 let adr = 0x100 + sp as u16;
                 let data = self.getp(false);
                 self.mem.set(adr, data);
@@ -0,0 +1,177 @@
+use crate::config;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    GRILLE,
+    SHADOW,
+}
+impl Mask {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "grille" => Some(Mask::GRILLE),
+            "shadow" => Some(Mask::SHADOW),
+            _ => None,
+        }
+    }
+}
+
+// Makes the upscaled frame look like a consumer TV: barrel curvature, dark
+// gaps between scanlines, a phosphor mask and a little bloom. It runs on
+// whatever the filter or HD pack produced and writes a picture of the
+// window's size, so the mask lines up with real pixels. Only the visible
+// area inside the overscan crop is drawn.
+//
+// The beam is narrower on dark pixels than on bright ones, and the light
+// lost to the gaps and the mask is won back with a fixed gain, so strength
+// changes the look more than the brightness. The bloom mostly fills the
+// darkened parts. Curvature and the mask are worked out once per window
+// size.
+pub struct Crt {
+    strength: f32,
+    width: usize,
+    height: usize,
+    points: Vec<Option<Point>>,
+    glow: Vec<[f32; 3]>,
+    temp: Vec<[f32; 3]>,
+}
+// Where an output pixel lands on the NES picture: the horizontal position,
+// the line and the squared distance from its middle, and which phosphor of
+// the mask it is.
+#[derive(Clone, Copy)]
+struct Point {
+    x: f32,
+    edge: f32,
+    line: u16,
+    phosphor: u8,
+}
+impl Crt {
+    pub fn new(config: &config::Config, width: u32, height: u32) -> Self {
+        let strength = config.crt.clamp(0.0, 1.0);
+        let (width, height) = (width as usize, height as usize);
+        let curvature = 0.08 * strength;
+        let left = config.overscan.left as f32;
+        let top = config.overscan.top as f32;
+        let visible = (config.width() as f32, config.height() as f32);
+        let mut points = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+                let cu = u * (1.0 + curvature * v * v);
+                let cv = v * (1.0 + curvature * u * u);
+                if cu.abs() > 1.0 || cv.abs() > 1.0 {
+                    points.push(None);
+                    continue;
+                }
+                let ny = top + (cv + 1.0) / 2.0 * visible.1;
+                let line = (ny as usize).min(HEIGHT - 1);
+                let d = (ny - line as f32 - 0.5) * 2.0;
+                points.push(Some(Point {
+                    x: left + (cu + 1.0) / 2.0 * visible.0,
+                    edge: d * d,
+                    line: line as u16,
+                    phosphor: match config.crt_mask {
+                        Mask::GRILLE => x % 3,
+                        Mask::SHADOW => (x + y * 3) % 6 / 2,
+                    } as u8,
+                }));
+            }
+        }
+        Self {
+            strength,
+            width,
+            height,
+            points,
+            glow: vec![[0.0; 3]; WIDTH * HEIGHT],
+            temp: vec![[0.0; 3]; WIDTH * HEIGHT],
+        }
+    }
+    pub fn size(&self) -> (u32, u32) {
+        return (self.width as u32, self.height as u32);
+    }
+    // `scale` is how much larger than 256x240 the input is.
+    pub fn apply(&mut self, rgb: &[u8], scale: (u32, u32), out: &mut Vec<u8>) {
+        let (sx, sy) = (scale.0 as usize, scale.1 as usize);
+        let stride = WIDTH * sx;
+        self.make_glow(rgb, sx, sy);
+
+        let scan = 0.6 * self.strength;
+        let mask = 0.35 * self.strength;
+        let bloom = 0.3 * self.strength;
+        let gain = 1.0 / ((1.0 - mask * 2.0 / 3.0) * (1.0 - scan / 4.0));
+        out.resize(self.width * self.height * 3, 0);
+        for (point, pixel) in self.points.iter().zip(out.chunks_exact_mut(3)) {
+            let point = match point {
+                Some(point) => point,
+                None => {
+                    pixel.fill(0);
+                    continue;
+                }
+            };
+            let row = point.line as usize;
+            let line = &rgb[(row * sy + sy / 2) * stride * 3..];
+            let fx = (point.x * sx as f32 - 0.5).max(0.0);
+            let x0 = (fx as usize).min(stride - 1);
+            let x1 = (x0 + 1).min(stride - 1);
+            let t = fx - x0 as f32;
+            let mut color = [0.0; 3];
+            for c in 0..3 {
+                let a = line[x0 * 3 + c] as f32;
+                let b = line[x1 * 3 + c] as f32;
+                color[c] = (a + (b - a) * t) / 255.0;
+            }
+
+            let luma = 0.3 * color[0] + 0.59 * color[1] + 0.11 * color[2];
+            let beam = (1.0 - scan * point.edge * (1.0 - 0.5 * luma)).max(0.0);
+            let glow = self.glow[row * WIDTH + (point.x as usize).min(WIDTH - 1)];
+            for c in 0..3 {
+                let m = if c == point.phosphor as usize {
+                    1.0
+                } else {
+                    1.0 - mask
+                };
+                let lit = beam * m;
+                let v = color[c] * lit * gain + glow[c] * bloom * (1.0 - lit);
+                pixel[c] = (v * 255.0).min(255.0) as u8;
+            }
+        }
+    }
+    // A blurred copy of the frame at NES resolution for the bloom.
+    fn make_glow(&mut self, rgb: &[u8], sx: usize, sy: usize) {
+        const WEIGHTS: [f32; 5] = [1.0, 2.0, 3.0, 2.0, 1.0];
+        let stride = WIDTH * sx;
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let i = ((y * sy + sy / 2) * stride + x * sx + sx / 2) * 3;
+                for c in 0..3 {
+                    self.glow[y * WIDTH + x][c] = rgb[i + c] as f32 / 255.0;
+                }
+            }
+        }
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let mut sum = [0.0; 3];
+                for (k, w) in WEIGHTS.iter().enumerate() {
+                    let px = (x + k).saturating_sub(2).min(WIDTH - 1);
+                    let p = self.glow[y * WIDTH + px];
+                    (0..3).for_each(|c| sum[c] += p[c] * w / 9.0);
+                }
+                self.temp[y * WIDTH + x] = sum;
+            }
+        }
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let mut sum = [0.0; 3];
+                for (k, w) in WEIGHTS.iter().enumerate() {
+                    let py = (y + k).saturating_sub(2).min(HEIGHT - 1);
+                    let p = self.temp[py * WIDTH + x];
+                    (0..3).for_each(|c| sum[c] += p[c] * w / 9.0);
+                }
+                self.glow[y * WIDTH + x] = sum;
+            }
+        }
+    }
+}
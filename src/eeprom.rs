@@ -0,0 +1,325 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EepromKind {
+    X24C01,
+    X24C02,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Idle,
+    ChipAddress,
+    Address,
+    Read,
+    Write,
+    SendAck,
+    WaitAck,
+}
+
+// Bit-level model of the serial EEPROMs on Bandai boards. The 24C01 takes a
+// 7-bit word address straight after START and shifts data LSB first; the
+// 24C02 is a standard I2C device with a chip address byte, MSB first.
+pub struct Eeprom {
+    kind: EepromKind,
+    pub data: Vec<u8>,
+    mode: Mode,
+    next_mode: Mode,
+    chip_address: u8,
+    address: u8,
+    value: u8,
+    counter: u8,
+    output: u8,
+    prev_scl: u8,
+    prev_sda: u8,
+    pub modified: bool,
+}
+impl Eeprom {
+    pub fn new(kind: EepromKind) -> Self {
+        let size = match kind {
+            EepromKind::X24C01 => 128,
+            EepromKind::X24C02 => 256,
+        };
+        Self {
+            kind,
+            data: vec![0xff; size],
+            mode: Mode::Idle,
+            next_mode: Mode::Idle,
+            chip_address: 0,
+            address: 0,
+            value: 0,
+            counter: 0,
+            output: 1,
+            prev_scl: 0,
+            prev_sda: 0,
+            modified: false,
+        }
+    }
+    pub fn load(&mut self, buf: &[u8]) {
+        let len = self.data.len().min(buf.len());
+        self.data[..len].copy_from_slice(&buf[..len]);
+    }
+    pub fn read(&mut self) -> u8 {
+        return self.output;
+    }
+    pub fn write(&mut self, scl: u8, sda: u8) {
+        if self.prev_scl != 0 && scl != 0 && sda < self.prev_sda {
+            // START: SDA falls while SCL is high.
+            self.mode = match self.kind {
+                EepromKind::X24C01 => Mode::Address,
+                EepromKind::X24C02 => Mode::ChipAddress,
+            };
+            if self.kind == EepromKind::X24C01 {
+                self.address = 0;
+            }
+            self.counter = 0;
+            self.output = 1;
+        } else if self.prev_scl != 0 && scl != 0 && sda > self.prev_sda {
+            // STOP: SDA rises while SCL is high.
+            self.mode = Mode::Idle;
+            self.output = 1;
+        } else if scl > self.prev_scl {
+            self.clock_rise(sda);
+        } else if scl < self.prev_scl {
+            self.clock_fall();
+        }
+        self.prev_scl = scl;
+        self.prev_sda = sda;
+    }
+    fn clock_rise(&mut self, sda: u8) {
+        match self.mode {
+            Mode::ChipAddress => {
+                self.chip_address = self.write_bit(self.chip_address, sda);
+            }
+            Mode::Address => {
+                if self.kind == EepromKind::X24C01 && self.counter == 7 {
+                    // The 8th bit after the 7-bit address selects read or write.
+                    self.counter = 8;
+                    if sda != 0 {
+                        self.next_mode = Mode::Read;
+                        self.value = self.data[self.address as usize];
+                    } else {
+                        self.next_mode = Mode::Write;
+                    }
+                } else {
+                    self.address = self.write_bit(self.address, sda);
+                }
+            }
+            Mode::Read => {
+                if self.counter < 8 {
+                    self.output = (self.value >> self.bit_index()) & 0x01;
+                    self.counter += 1;
+                }
+            }
+            Mode::Write => {
+                self.value = self.write_bit(self.value, sda);
+            }
+            Mode::SendAck => {
+                self.output = 0;
+            }
+            Mode::WaitAck => {
+                if sda == 0 {
+                    self.next_mode = Mode::Read;
+                    self.value = self.data[self.address as usize];
+                } else {
+                    self.next_mode = Mode::Idle;
+                }
+            }
+            Mode::Idle => {}
+        }
+    }
+    fn clock_fall(&mut self) {
+        let mask = (self.data.len() - 1) as u8;
+        match self.mode {
+            Mode::ChipAddress => {
+                if self.counter == 8 {
+                    if (self.chip_address & 0xf0) == 0xa0 {
+                        self.mode = Mode::SendAck;
+                        if (self.chip_address & 0x01) != 0 {
+                            self.next_mode = Mode::Read;
+                            self.value = self.data[self.address as usize];
+                        } else {
+                            self.next_mode = Mode::Address;
+                        }
+                    } else {
+                        self.mode = Mode::Idle;
+                    }
+                    self.counter = 0;
+                    self.output = 1;
+                }
+            }
+            Mode::Address => {
+                if self.counter == 8 {
+                    self.mode = Mode::SendAck;
+                    if self.kind == EepromKind::X24C02 {
+                        self.next_mode = Mode::Write;
+                    }
+                    self.counter = 0;
+                    self.output = 1;
+                }
+            }
+            Mode::Read => {
+                if self.counter == 8 {
+                    self.mode = Mode::WaitAck;
+                    self.address = (self.address + 1) & mask;
+                }
+            }
+            Mode::Write => {
+                if self.counter == 8 {
+                    self.data[(self.address & mask) as usize] = self.value;
+                    self.modified = true;
+                    self.address = (self.address + 1) & mask;
+                    self.mode = Mode::SendAck;
+                    self.next_mode = match self.kind {
+                        EepromKind::X24C01 => Mode::Idle,
+                        EepromKind::X24C02 => Mode::Write,
+                    };
+                    self.counter = 0;
+                }
+            }
+            Mode::SendAck | Mode::WaitAck => {
+                self.mode = self.next_mode;
+                self.counter = 0;
+                self.output = 1;
+            }
+            Mode::Idle => {}
+        }
+    }
+    fn bit_index(&mut self) -> u8 {
+        match self.kind {
+            EepromKind::X24C01 => self.counter,
+            EepromKind::X24C02 => 7 - self.counter,
+        }
+    }
+    fn write_bit(&mut self, dest: u8, sda: u8) -> u8 {
+        if self.counter >= 8 {
+            return dest;
+        }
+        let bit = self.bit_index();
+        self.counter += 1;
+        return (dest & !(1 << bit)) | ((sda & 0x01) << bit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(eeprom: &mut Eeprom) {
+        eeprom.write(0, 1);
+        eeprom.write(1, 1);
+        eeprom.write(1, 0);
+        eeprom.write(0, 0);
+    }
+    fn stop(eeprom: &mut Eeprom) {
+        eeprom.write(0, 0);
+        eeprom.write(1, 0);
+        eeprom.write(1, 1);
+    }
+    // One SCL pulse with SDA held; returns what the chip drives while SCL
+    // is high.
+    fn clock(eeprom: &mut Eeprom, sda: u8) -> u8 {
+        eeprom.write(0, sda);
+        eeprom.write(1, sda);
+        let bit = eeprom.read();
+        eeprom.write(0, sda);
+        return bit;
+    }
+    // Sends a byte and returns the acknowledge bit, 0 for ACK.
+    fn send(eeprom: &mut Eeprom, value: u8, msb_first: bool) -> u8 {
+        for i in 0..8 {
+            let shift = if msb_first { 7 - i } else { i };
+            clock(eeprom, (value >> shift) & 0x01);
+        }
+        return clock(eeprom, 1);
+    }
+    // Receives a byte, then answers with ACK (0) or NACK (1).
+    fn receive(eeprom: &mut Eeprom, msb_first: bool, ack: u8) -> u8 {
+        let mut value = 0;
+        for i in 0..8 {
+            let shift = if msb_first { 7 - i } else { i };
+            value |= clock(eeprom, 1) << shift;
+        }
+        clock(eeprom, ack);
+        return value;
+    }
+
+    #[test]
+    fn x24c02_page_write_and_read_back() {
+        let mut eeprom = Eeprom::new(EepromKind::X24C02);
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0xa0, true), 0);
+        assert_eq!(send(&mut eeprom, 0x10, true), 0);
+        assert_eq!(send(&mut eeprom, 0x5a, true), 0);
+        assert_eq!(send(&mut eeprom, 0x3c, true), 0);
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data[0x10], 0x5a);
+        assert_eq!(eeprom.data[0x11], 0x3c);
+        assert!(eeprom.modified);
+
+        // Random read: set the address, then a repeated START with the
+        // read bit, and keep reading sequentially.
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0xa0, true), 0);
+        assert_eq!(send(&mut eeprom, 0x10, true), 0);
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0xa1, true), 0);
+        assert_eq!(receive(&mut eeprom, true, 0), 0x5a);
+        assert_eq!(receive(&mut eeprom, true, 1), 0x3c);
+        stop(&mut eeprom);
+        assert_eq!(eeprom.read(), 1);
+    }
+
+    #[test]
+    fn x24c02_ignores_other_chip_addresses() {
+        let mut eeprom = Eeprom::new(EepromKind::X24C02);
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0x50, true), 1);
+        assert_eq!(send(&mut eeprom, 0x00, true), 1);
+        stop(&mut eeprom);
+        assert!(!eeprom.modified);
+        assert!(eeprom.data.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn x24c02_does_not_answer_other_device_codes() {
+        let mut eeprom = Eeprom::new(EepromKind::X24C02);
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0xe0, true), 1);
+        assert_eq!(eeprom.read(), 1);
+        assert_eq!(send(&mut eeprom, 0x00, true), 1);
+        stop(&mut eeprom);
+        assert_eq!(eeprom.read(), 1);
+        assert!(!eeprom.modified);
+    }
+
+    #[test]
+    fn x24c01_write_and_read_back() {
+        let mut eeprom = Eeprom::new(EepromKind::X24C01);
+        // 7-bit word address LSB first, then the read/write bit.
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0x25, false), 0);
+        assert_eq!(send(&mut eeprom, 0xc3, false), 0);
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data[0x25], 0xc3);
+        assert!(eeprom.modified);
+
+        start(&mut eeprom);
+        assert_eq!(send(&mut eeprom, 0x80 | 0x25, false), 0);
+        assert_eq!(receive(&mut eeprom, false, 1), 0xc3);
+        stop(&mut eeprom);
+        assert_eq!(eeprom.read(), 1);
+    }
+
+    #[test]
+    fn stop_ends_a_write_before_its_last_bit() {
+        let mut eeprom = Eeprom::new(EepromKind::X24C02);
+        start(&mut eeprom);
+        send(&mut eeprom, 0xa0, true);
+        send(&mut eeprom, 0x00, true);
+        for _ in 0..4 {
+            clock(&mut eeprom, 0);
+        }
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data[0], 0xff);
+        assert!(!eeprom.modified);
+    }
+}
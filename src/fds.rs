@@ -0,0 +1,591 @@
+use crate::fds_sound;
+use crate::io;
+use crate::irq;
+use crate::mapper;
+use crate::ppu;
+use crate::rom;
+use std::fs;
+use std::path::Path;
+
+const FDS_HEADER_SIZE: usize = 16;
+const FDS_SIDE_SIZE: usize = 65500;
+const QD_SIDE_SIZE: usize = 65536;
+const BIOS_SIZE: usize = 0x2000;
+
+// The drive sees 28300 bits of gap before the first block and 976 bits
+// between blocks; images store neither, so they are added on load.
+const GAP_LEAD_IN: usize = 28300 / 8;
+const GAP_BLOCK: usize = 976 / 8;
+const DISK_SIDE_CAPACITY: usize = 0x12000;
+
+const DISK_MOTOR_DELAY: usize = 50000;
+const DISK_BYTE_CYCLES: usize = 150;
+const DISK_INSERT_DELAY: usize = 900000;
+
+pub fn is_fds_image(buf: &Vec<u8>) -> bool {
+    if buf.len() >= 4 && buf[0..4] == [0x46, 0x44, 0x53, 0x1a] {
+        return true;
+    }
+    return buf.len() >= 15 && buf[0] == 0x01 && &buf[1..15] == b"*NINTENDO-HVC*";
+}
+
+pub struct Fds {
+    pub rom: rom::Rom,
+    pub ppu: ppu::Ppu,
+    pub io: io::Io,
+    sound: fds_sound::FdsSound,
+
+    disk_sides: Vec<Vec<u8>>,
+    disk_number: Option<usize>,
+    next_disk: usize,
+    insert_delay: usize,
+    disk_modified: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_repeat: bool,
+    timer_irq: bool,
+    disk_irq: bool,
+
+    disk_reg_enabled: bool,
+    sound_reg_enabled: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    ext_data: u8,
+
+    end_of_head: bool,
+    scanning_disk: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    disk_position: usize,
+    delay: usize,
+    crc: u16,
+}
+impl mapper::MapperBase for Fds {
+    fn rom(&mut self) -> &mut rom::Rom {
+        &mut self.rom
+    }
+    fn ppu(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+    fn io(&mut self) -> &mut io::Io {
+        &mut self.io
+    }
+    fn set_rom(&mut self, buf: Vec<u8>) -> Result<(), String> {
+        println!("Fds set_rom");
+        self.load(buf)?;
+        self.ppu.start(&mut self.rom);
+        return Ok(());
+    }
+    fn read_low(&mut self, addr: u16) -> u8 {
+        if addr >= 0x4040 && addr <= 0x4097 {
+            if self.sound_reg_enabled {
+                return self.sound.read_reg(addr);
+            }
+            return 0x40;
+        }
+        if !self.disk_reg_enabled {
+            return 0x40;
+        }
+        match addr {
+            0x4030 => {
+                let mut value = 0x00;
+                if self.timer_irq {
+                    value |= 0x01;
+                }
+                if self.transfer_complete {
+                    value |= 0x02;
+                }
+                if self.end_of_head {
+                    value |= 0x40;
+                }
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                return value;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                return self.read_data;
+            }
+            0x4032 => {
+                let mut value = 0x40;
+                if self.disk_number.is_none() {
+                    value |= 0x07;
+                } else if !self.scanning_disk {
+                    value |= 0x02;
+                }
+                return value;
+            }
+            0x4033 => {
+                return 0x80;
+            }
+            _ => {}
+        }
+        return 0x40;
+    }
+    fn write_low(&mut self, addr: u16, data: u8) {
+        if addr >= 0x4040 && addr <= 0x4097 {
+            if self.sound_reg_enabled {
+                self.sound.write_reg(addr, data);
+            }
+            return;
+        }
+        if !self.disk_reg_enabled && addr >= 0x4024 && addr <= 0x4026 {
+            return;
+        }
+        match addr {
+            0x4020 => {
+                self.irq_reload = (self.irq_reload & 0xff00) | data as u16;
+            }
+            0x4021 => {
+                self.irq_reload = (self.irq_reload & 0x00ff) | ((data as u16) << 8);
+            }
+            0x4022 => {
+                self.irq_repeat = (data & 0x01) != 0;
+                self.irq_enabled = (data & 0x02) != 0 && self.disk_reg_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_reg_enabled = (data & 0x01) != 0;
+                self.sound_reg_enabled = (data & 0x02) != 0;
+                if !self.disk_reg_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = (data & 0x01) != 0;
+                self.reset_transfer = (data & 0x02) != 0;
+                self.read_mode = (data & 0x04) != 0;
+                self.crc_control = (data & 0x10) != 0;
+                self.disk_ready = (data & 0x40) != 0;
+                self.disk_irq_enabled = (data & 0x80) != 0;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.ppu.set_mirroring(if (data & 0x08) != 0 {
+                    rom::Mirroring::HORIZONTAL
+                } else {
+                    rom::Mirroring::VERTICAL
+                });
+            }
+            0x4026 => {
+                self.ext_data = data;
+            }
+            _ => {}
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr < 0xe000 {
+            let page = ((addr - 0x8000) >> 13) as usize;
+            self.rom.roms[page][(addr & 0x1fff) as usize] = data;
+        }
+    }
+    fn cpusync(&mut self, cpuclock: usize, irq: &mut irq::Irq) {
+        for _ in 0..cpuclock {
+            self.clock_irq();
+            self.clock_disk();
+            self.sound.clock();
+        }
+        irq.set_irq(self.timer_irq || self.disk_irq);
+    }
+    fn out_exsound(&mut self) -> i32 {
+        return self.sound.output() as i32;
+    }
+    fn switch_disk(&mut self) {
+        if self.disk_sides.is_empty() {
+            return;
+        }
+        self.next_disk = match self.disk_number {
+            Some(n) => (n + 1) % self.disk_sides.len(),
+            None => (self.next_disk + 1) % self.disk_sides.len(),
+        };
+        self.disk_number = None;
+        self.insert_delay = DISK_INSERT_DELAY;
+        println!(
+            "FDS: disk ejected, inserting disk {} side {}",
+            self.next_disk / 2 + 1,
+            if self.next_disk % 2 == 0 { "A" } else { "B" }
+        );
+    }
+    fn save(&mut self) {
+        if !self.disk_modified {
+            return;
+        }
+        let mut buf = vec![0x46, 0x44, 0x53, 0x1a, self.disk_sides.len() as u8];
+        buf.resize(FDS_HEADER_SIZE, 0);
+        for side in self.disk_sides.iter() {
+            buf.extend(strip_gaps(side));
+        }
+        let path = self.save_path();
+        match fs::write(&path, buf) {
+            Result::Ok(_) => {
+                println!("FDS: saved disk to {}", path);
+                self.disk_modified = false;
+            }
+            Result::Err(err) => {
+                eprintln!("Cannot write FDS save file: {} ({})", path, err);
+            }
+        }
+    }
+}
+impl Fds {
+    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
+        Self {
+            rom,
+            ppu,
+            io,
+            sound: fds_sound::FdsSound::new(),
+
+            disk_sides: Vec::new(),
+            disk_number: Some(0),
+            next_disk: 0,
+            insert_delay: 0,
+            disk_modified: false,
+
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_repeat: false,
+            timer_irq: false,
+            disk_irq: false,
+
+            disk_reg_enabled: true,
+            sound_reg_enabled: true,
+
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            ext_data: 0,
+
+            end_of_head: true,
+            scanning_disk: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            disk_position: 0,
+            delay: 0,
+            crc: 0,
+        }
+    }
+    fn load(&mut self, buf: Vec<u8>) -> Result<(), String> {
+        let bios = self.load_bios()?;
+        let image = match self.load_save() {
+            Some(save) => save,
+            None => buf,
+        };
+        self.load_disk(&image)?;
+
+        for i in 0..3 {
+            self.rom.roms[i] = vec![0; 0x2000];
+        }
+        self.rom.roms[3] = bios;
+        return Ok(());
+    }
+    fn load_bios(&mut self) -> Result<Vec<u8>, String> {
+        let dir = Path::new(&self.rom.filename)
+            .parent()
+            .unwrap_or(Path::new(""));
+        let candidates = [
+            dir.join("disksys.rom"),
+            Path::new("disksys.rom").to_path_buf(),
+        ];
+        for path in candidates.iter() {
+            if let Result::Ok(buf) = fs::read(path) {
+                if buf.len() >= BIOS_SIZE {
+                    return Ok(buf[(buf.len() - BIOS_SIZE)..].to_vec());
+                }
+            }
+        }
+        return Err("Cannot open FDS BIOS: disksys.rom is required to run .fds images".to_string());
+    }
+    fn save_path(&self) -> String {
+        let path = Path::new(&self.rom.filename).with_extension("sav");
+        return path.to_string_lossy().to_string();
+    }
+    fn load_save(&mut self) -> Option<Vec<u8>> {
+        match fs::read(self.save_path()) {
+            Result::Ok(buf) if is_fds_image(&buf) => {
+                println!("FDS: loading disk from {}", self.save_path());
+                Some(buf)
+            }
+            _ => None,
+        }
+    }
+    fn load_disk(&mut self, buf: &Vec<u8>) -> Result<(), String> {
+        let (data, side_size, has_crc) =
+            if buf.len() >= FDS_HEADER_SIZE && buf[0..4] == [0x46, 0x44, 0x53, 0x1a] {
+                (&buf[FDS_HEADER_SIZE..], FDS_SIDE_SIZE, false)
+            } else if buf.len() % QD_SIDE_SIZE == 0 {
+                (&buf[..], QD_SIDE_SIZE, true)
+            } else {
+                (&buf[..], FDS_SIDE_SIZE, false)
+            };
+
+        self.disk_sides = data
+            .chunks(side_size)
+            .filter(|side| side.len() == side_size)
+            .map(|side| add_gaps(side, has_crc))
+            .collect();
+        if self.disk_sides.is_empty() {
+            return Err("Invalid *.fds file: no complete disk side".to_string());
+        }
+        println!("FDS: {} disk side(s)", self.disk_sides.len());
+        return Ok(());
+    }
+    fn clock_irq(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.disk_number = Some(self.next_disk);
+            }
+        }
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.timer_irq = true;
+                self.irq_counter = self.irq_reload;
+                if !self.irq_repeat {
+                    self.irq_enabled = false;
+                }
+            } else {
+                self.irq_counter -= 1;
+            }
+        }
+    }
+    fn clock_disk(&mut self) {
+        let disk = match self.disk_number {
+            Some(disk) if self.motor_on => disk,
+            _ => {
+                self.end_of_head = true;
+                self.scanning_disk = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning_disk {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = DISK_MOTOR_DELAY;
+            self.end_of_head = false;
+            self.disk_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning_disk = true;
+        let mut need_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.disk_sides[disk][self.disk_position];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The $80 start mark ends the gap but is not handed to the CPU.
+                self.gap_ended = true;
+                need_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0x00;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+            if !self.disk_ready {
+                data = 0x00;
+                self.crc = 0;
+            }
+            if !self.crc_control {
+                self.update_crc(data);
+            } else {
+                if !self.previous_crc_control {
+                    self.update_crc(0x00);
+                    self.update_crc(0x00);
+                }
+                data = (self.crc & 0xff) as u8;
+                self.crc >>= 8;
+            }
+            if self.disk_position >= 2 {
+                self.disk_sides[disk][self.disk_position - 2] = data;
+                self.disk_modified = true;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.disk_position += 1;
+        if self.disk_position >= self.disk_sides[disk].len() {
+            self.motor_on = false;
+        } else {
+            self.delay = DISK_BYTE_CYCLES;
+        }
+    }
+    fn update_crc(&mut self, value: u8) {
+        self.crc = crc_step(self.crc, value);
+    }
+}
+
+fn crc_step(mut crc: u16, value: u8) -> u16 {
+    for n in 0..8 {
+        let carry = (crc & 0x01) != 0;
+        crc >>= 1;
+        if carry ^ ((value >> n) & 0x01 != 0) {
+            crc ^= 0x8408;
+        }
+    }
+    return crc;
+}
+
+fn block_length(side: &[u8], pos: usize, file_size: usize) -> Option<usize> {
+    match side[pos] {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn add_gaps(side: &[u8], has_crc: bool) -> Vec<u8> {
+    let mut disk = vec![0; GAP_LEAD_IN];
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    while pos < side.len() {
+        let len = match block_length(side, pos, file_size) {
+            Some(len) if pos + len <= side.len() => len,
+            _ => break,
+        };
+        if side[pos] == 3 {
+            file_size = side[pos + 13] as usize | ((side[pos + 14] as usize) << 8);
+        }
+
+        let mut crc = crc_step(0, 0x80);
+        for &data in side[pos..(pos + len)].iter() {
+            crc = crc_step(crc, data);
+        }
+        crc = crc_step(crc_step(crc, 0x00), 0x00);
+
+        disk.push(0x80);
+        disk.extend_from_slice(&side[pos..(pos + len)]);
+        disk.push((crc & 0xff) as u8);
+        disk.push((crc >> 8) as u8);
+        disk.extend(vec![0; GAP_BLOCK]);
+
+        pos += len;
+        if has_crc {
+            pos += 2;
+        }
+    }
+    if disk.len() < DISK_SIDE_CAPACITY {
+        disk.resize(DISK_SIDE_CAPACITY, 0);
+    }
+    return disk;
+}
+
+fn strip_gaps(disk: &Vec<u8>) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    loop {
+        while pos < disk.len() && disk[pos] != 0x80 {
+            pos += 1;
+        }
+        pos += 1;
+        if pos >= disk.len() {
+            break;
+        }
+        let len = match block_length(disk, pos, file_size) {
+            Some(len) if pos + len <= disk.len() => len,
+            _ => break,
+        };
+        if disk[pos] == 3 {
+            file_size = disk[pos + 13] as usize | ((disk[pos + 14] as usize) << 8);
+        }
+        side.extend_from_slice(&disk[pos..(pos + len)]);
+        pos += len + 2;
+    }
+    side.resize(FDS_SIDE_SIZE, 0);
+    return side;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A disk side in .fds layout: disk info, file count, then a header and
+    // data block per file, padded with zeros.
+    fn make_side(files: &[Vec<u8>]) -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0x00);
+        side.extend_from_slice(&[0x02, files.len() as u8]);
+        for (i, data) in files.iter().enumerate() {
+            let mut header = vec![0x03, i as u8, i as u8];
+            header.extend_from_slice(b"FILE    ");
+            header.extend_from_slice(&[0x00, 0x60]);
+            header.extend_from_slice(&[(data.len() & 0xff) as u8, (data.len() >> 8) as u8]);
+            header.push(0x00);
+            side.extend(header);
+            side.push(0x04);
+            side.extend_from_slice(data);
+        }
+        side.resize(FDS_SIDE_SIZE, 0);
+        return side;
+    }
+
+    #[test]
+    fn gaps_round_trip() {
+        // The data holds the 0x80 gap end marker to show it is skipped by
+        // length rather than searched for.
+        let files = vec![
+            (0..300).map(|i| (i * 7) as u8).collect(),
+            vec![0x80; 40],
+            vec![0xa5],
+        ];
+        let side = make_side(&files);
+        let disk = add_gaps(&side, false);
+        assert_eq!(disk.len(), DISK_SIDE_CAPACITY);
+        assert_eq!(strip_gaps(&disk), side);
+    }
+}
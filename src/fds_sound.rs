@@ -0,0 +1,270 @@
+const MOD_TABLE_STEP: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MOD_TABLE_RESET: u8 = 4;
+const MASTER_VOLUME: [u32; 4] = [36, 24, 17, 14];
+
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    envelope_off: bool,
+    increase: bool,
+    frequency: u16,
+    timer: u32,
+    master_speed: u8,
+}
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            speed: 0,
+            gain: 0,
+            envelope_off: false,
+            increase: false,
+            frequency: 0,
+            timer: 0,
+            master_speed: 0xe8,
+        }
+    }
+    fn write_reg(&mut self, addr: u16, value: u8) {
+        match addr & 0x03 {
+            0 => {
+                self.speed = value & 0x3f;
+                self.increase = (value & 0x40) != 0;
+                self.envelope_off = (value & 0x80) != 0;
+                self.reset_timer();
+                if self.envelope_off {
+                    self.gain = self.speed;
+                }
+            }
+            2 => {
+                self.frequency = (self.frequency & 0x0f00) | value as u16;
+            }
+            3 => {
+                self.frequency = (self.frequency & 0x00ff) | (((value & 0x0f) as u16) << 8);
+            }
+            _ => {}
+        }
+    }
+    fn tick(&mut self) -> bool {
+        if !self.envelope_off && self.master_speed > 0 {
+            if self.timer > 1 {
+                self.timer -= 1;
+            } else {
+                self.reset_timer();
+                if self.increase && self.gain < 32 {
+                    self.gain += 1;
+                } else if !self.increase && self.gain > 0 {
+                    self.gain -= 1;
+                }
+                return true;
+            }
+        }
+        return false;
+    }
+    fn reset_timer(&mut self) {
+        self.timer = 8 * (self.speed as u32 + 1) * self.master_speed as u32;
+    }
+}
+
+pub struct FdsSound {
+    wave_table: Vec<u8>,
+    wave_write_enabled: bool,
+    wave_overflow: u16,
+    wave_position: usize,
+    halt_waveform: bool,
+    disable_envelopes: bool,
+    master_volume: usize,
+    output: u8,
+
+    volume: Envelope,
+    modulator: Envelope,
+    mod_table: Vec<u8>,
+    mod_position: usize,
+    mod_counter: i8,
+    mod_disabled: bool,
+    mod_overflow: u16,
+    mod_output: i32,
+}
+impl FdsSound {
+    pub fn new() -> Self {
+        Self {
+            wave_table: vec![0; 64],
+            wave_write_enabled: false,
+            wave_overflow: 0,
+            wave_position: 0,
+            halt_waveform: false,
+            disable_envelopes: false,
+            master_volume: 0,
+            output: 0,
+
+            volume: Envelope::new(),
+            modulator: Envelope::new(),
+            mod_table: vec![0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_disabled: false,
+            mod_overflow: 0,
+            mod_output: 0,
+        }
+    }
+    pub fn read_reg(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407f => {
+                return self.wave_table[(addr & 0x3f) as usize] | 0x40;
+            }
+            0x4090 => {
+                return self.volume.gain | 0x40;
+            }
+            0x4092 => {
+                return self.modulator.gain | 0x40;
+            }
+            _ => {}
+        }
+        return 0x40;
+    }
+    pub fn write_reg(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407f => {
+                if self.wave_write_enabled {
+                    self.wave_table[(addr & 0x3f) as usize] = value & 0x3f;
+                }
+            }
+            0x4080 | 0x4082 => {
+                self.volume.write_reg(addr, value);
+            }
+            0x4083 => {
+                self.disable_envelopes = (value & 0x40) != 0;
+                self.halt_waveform = (value & 0x80) != 0;
+                if self.disable_envelopes {
+                    self.volume.reset_timer();
+                    self.modulator.reset_timer();
+                }
+                self.volume.write_reg(addr, value);
+            }
+            0x4084 | 0x4086 => {
+                self.modulator.write_reg(addr, value);
+            }
+            0x4085 => {
+                self.set_mod_counter((value & 0x7f) as i8);
+            }
+            0x4087 => {
+                self.modulator.write_reg(addr, value);
+                self.mod_disabled = (value & 0x80) != 0;
+                if self.mod_disabled {
+                    self.mod_overflow = 0;
+                }
+            }
+            0x4088 => {
+                if self.mod_disabled {
+                    self.mod_table[self.mod_position] = value & 0x07;
+                    self.mod_table[(self.mod_position + 1) & 0x3f] = value & 0x07;
+                    self.mod_position = (self.mod_position + 2) & 0x3f;
+                }
+            }
+            0x4089 => {
+                self.master_volume = (value & 0x03) as usize;
+                self.wave_write_enabled = (value & 0x80) != 0;
+            }
+            0x408a => {
+                self.volume.master_speed = value;
+                self.modulator.master_speed = value;
+            }
+            _ => {}
+        }
+    }
+    pub fn clock(&mut self) {
+        let frequency = self.volume.frequency;
+        if !self.halt_waveform && !self.disable_envelopes {
+            self.volume.tick();
+            if self.modulator.tick() {
+                self.update_mod_output(frequency);
+            }
+        }
+        if self.tick_modulator() {
+            self.update_mod_output(frequency);
+        }
+
+        if self.halt_waveform {
+            self.wave_position = 0;
+            self.update_output();
+        } else {
+            self.update_output();
+            let pitch = frequency as i32 + self.mod_pitch();
+            if pitch > 0 && !self.wave_write_enabled {
+                let (counter, overflow) = self.wave_overflow.overflowing_add(pitch as u16);
+                self.wave_overflow = counter;
+                if overflow {
+                    self.wave_position = (self.wave_position + 1) & 0x3f;
+                }
+            }
+        }
+    }
+    pub fn output(&mut self) -> u8 {
+        return self.output;
+    }
+    fn update_output(&mut self) {
+        let gain = if self.volume.gain < 32 {
+            self.volume.gain
+        } else {
+            32
+        };
+        let level = gain as u32 * MASTER_VOLUME[self.master_volume];
+        self.output = ((self.wave_table[self.wave_position] as u32 * level) / 1152) as u8;
+    }
+    fn mod_enabled(&mut self) -> bool {
+        return !self.mod_disabled && self.modulator.frequency > 0;
+    }
+    fn mod_pitch(&mut self) -> i32 {
+        if self.mod_enabled() {
+            return self.mod_output;
+        }
+        return 0;
+    }
+    fn set_mod_counter(&mut self, value: i8) {
+        let mut counter = value as i32;
+        if counter >= 64 {
+            counter -= 128;
+        } else if counter < -64 {
+            counter += 128;
+        }
+        self.mod_counter = counter as i8;
+    }
+    fn tick_modulator(&mut self) -> bool {
+        if !self.mod_enabled() {
+            return false;
+        }
+        let (counter, overflow) = self.mod_overflow.overflowing_add(self.modulator.frequency);
+        self.mod_overflow = counter;
+        if !overflow {
+            return false;
+        }
+        let step = self.mod_table[self.mod_position];
+        if step == MOD_TABLE_RESET {
+            self.set_mod_counter(0);
+        } else {
+            let counter = self.mod_counter as i32 + MOD_TABLE_STEP[step as usize] as i32;
+            self.set_mod_counter(counter as i8);
+        }
+        self.mod_position = (self.mod_position + 1) & 0x3f;
+        return true;
+    }
+    fn update_mod_output(&mut self, frequency: u16) {
+        // Pitch modulation arithmetic as described on the nesdev wiki.
+        let mut temp = self.mod_counter as i32 * self.modulator.gain as i32;
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && (temp & 0x80) == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= frequency as i32;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+}
@@ -0,0 +1,35 @@
+use crate::config;
+use crate::ntsc;
+use crate::scaler;
+use crate::scaler::ScaleKind;
+
+// Output filters turn a finished 256x240 frame into what goes on screen.
+// They see both the RGB frame and the palette index frame from the PPU and
+// may scale the picture by a fixed factor.
+pub trait Filter {
+    fn scale(&self) -> (u32, u32);
+    fn apply(&mut self, rgb: &[u8], indices: &[u16], out: &mut Vec<u8>);
+}
+
+// In the order the filter hotkey cycles through them.
+pub const FILTERS: &[&str] = &[
+    "none", "ntsc", "scale2x", "scale3x", "hq2x", "xbr2x", "xbr3x", "xbr4x",
+];
+
+pub fn new_filter(name: &str, config: &config::Config) -> Option<Box<dyn Filter>> {
+    let (kind, factor) = match name {
+        "none" => return None,
+        "ntsc" => return Some(Box::new(ntsc::NtscFilter::new(&config.ntsc))),
+        "scale2x" => (ScaleKind::SCALE, 2),
+        "scale3x" => (ScaleKind::SCALE, 3),
+        "hq2x" => (ScaleKind::HQ, 2),
+        "xbr2x" => (ScaleKind::XBR, 2),
+        "xbr3x" => (ScaleKind::XBR, 3),
+        "xbr4x" => (ScaleKind::XBR, 4),
+        _ => {
+            eprintln!("Unknown filter: {}", name);
+            return None;
+        }
+    };
+    return Some(Box::new(scaler::Scaler::new(kind, factor)));
+}
@@ -0,0 +1,74 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+// Keeps the last few seconds of frames as 6-bit palette indices so they can
+// be saved as an animated GIF with the 64 NES colours. Emphasis is dropped.
+//
+// GIF delays are in hundredths of a second and most viewers slow down
+// anything under 2, so only every other frame goes into the file, with the
+// delays rounded from the exact frame rate so that they add up.
+pub struct GifClip {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    frame_rate: f64,
+    skip: bool,
+}
+impl GifClip {
+    pub fn new(seconds: u32, frame_rate: f64) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            capacity: (seconds as f64 * frame_rate / 2.0).ceil() as usize,
+            frame_rate,
+            skip: false,
+        }
+    }
+    pub fn push(&mut self, indices: &[u16]) {
+        self.skip = !self.skip;
+        if self.skip || self.capacity == 0 {
+            return;
+        }
+        let mut frame = if self.frames.len() == self.capacity {
+            self.frames.pop_front().unwrap()
+        } else {
+            vec![0; WIDTH * HEIGHT]
+        };
+        for (out, index) in frame.iter_mut().zip(indices) {
+            *out = (index & 0x3f) as u8;
+        }
+        self.frames.push_back(frame);
+    }
+    pub fn save(&self, path: &str, palette: &[(u8, u8, u8)]) -> Result<(), String> {
+        if self.frames.is_empty() {
+            return Err("no frames yet".to_string());
+        }
+        let colors: Vec<u8> = palette.iter().flat_map(|&(r, g, b)| [r, g, b]).collect();
+        let file = File::create(path).map_err(|err| err.to_string())?;
+        let mut encoder =
+            gif::Encoder::new(BufWriter::new(file), WIDTH as u16, HEIGHT as u16, &colors)
+                .map_err(|err| err.to_string())?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|err| err.to_string())?;
+
+        let frame_time = 200.0 / self.frame_rate;
+        let mut shown = 0;
+        for (n, indices) in self.frames.iter().enumerate() {
+            let until = ((n + 1) as f64 * frame_time).round() as u64;
+            let frame = gif::Frame {
+                width: WIDTH as u16,
+                height: HEIGHT as u16,
+                delay: (until - shown) as u16,
+                buffer: Cow::Borrowed(indices),
+                ..gif::Frame::default()
+            };
+            shown = until;
+            encoder.write_frame(&frame).map_err(|err| err.to_string())?;
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,508 @@
+use crate::image;
+use crate::ppu;
+use crate::ppu::TilePixel;
+use crate::rom;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Mesen-style HD packs: a directory with a hires.txt that maps 8x8 tiles,
+// keyed by their CHR data and palette, to scale x scale times larger
+// pictures in PNG files.
+//
+// Supported tags are <scale>, <img>, <condition>, <tile> and <background>.
+// Tiles are given as 32 hex digits of CHR data or as a hex tile index into
+// CHR ROM; palettes as 8 hex digits, the universal background colour first.
+// A rule may be prefixed with [a&!b] to require conditions; the first rule
+// that matches wins, and rules marked as default also apply when no rule for
+// the exact palette matches. Backgrounds are drawn behind everything, fixed
+// to the screen.
+struct Image {
+    width: usize,
+    height: usize,
+    rgba: Vec<u8>,
+}
+
+enum Condition {
+    HMirror,
+    VMirror,
+    BgPriority,
+    Memory {
+        addr: usize,
+        op: String,
+        operand: usize,
+        constant: bool,
+        mask: u8,
+    },
+    FrameRange {
+        divisor: usize,
+        compare: usize,
+    },
+    Tile {
+        sprite: bool,
+        nearby: bool,
+        x: isize,
+        y: isize,
+        tile: [u8; 16],
+        palette: u32,
+    },
+}
+
+struct Rule {
+    conditions: Vec<(usize, bool)>,
+    image: usize,
+    x: usize,
+    y: usize,
+    brightness: f32,
+}
+
+// What conditions can look at while one frame is rendered.
+struct Frame<'a> {
+    sources: &'a [ppu::PixelSource],
+    ram: &'a [u8],
+    keys: [u32; 8],
+}
+
+pub struct HdPack {
+    pub scale: usize,
+    images: Vec<Image>,
+    conditions: Vec<Condition>,
+    names: HashMap<String, usize>,
+    tiles: HashMap<([u8; 16], u32), Vec<Rule>>,
+    defaults: HashMap<[u8; 16], Vec<Rule>>,
+    backgrounds: Vec<Rule>,
+    frame: usize,
+}
+impl HdPack {
+    pub fn load(dir: &str, rom: &rom::Rom) -> Result<Self, String> {
+        let path = Path::new(dir).join("hires.txt");
+        let text = fs::read_to_string(&path).map_err(|err| err.to_string())?;
+        let chr: Vec<u8> = if rom.chr_rom_page_count > 0 {
+            rom.chrrom_pages.concat()
+        } else {
+            Vec::new()
+        };
+        let mut pack = Self {
+            scale: 1,
+            images: Vec::new(),
+            conditions: Vec::new(),
+            names: HashMap::new(),
+            tiles: HashMap::new(),
+            defaults: HashMap::new(),
+            backgrounds: Vec::new(),
+            frame: 0,
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(err) = pack.parse_line(dir, line, &chr) {
+                eprintln!("HD pack: {}: {}", err, line);
+            }
+        }
+        println!(
+            "HD pack: {} tiles, {} images, scale {}",
+            pack.tiles.values().map(|rules| rules.len()).sum::<usize>()
+                + pack
+                    .defaults
+                    .values()
+                    .map(|rules| rules.len())
+                    .sum::<usize>(),
+            pack.images.len(),
+            pack.scale
+        );
+        return Ok(pack);
+    }
+    fn parse_line(&mut self, dir: &str, line: &str, chr: &[u8]) -> Result<(), String> {
+        let mut conditions = Vec::new();
+        let mut line = line;
+        if line.starts_with('[') {
+            let end = line.find(']').ok_or("missing ]")?;
+            for name in line[1..end].split('&') {
+                let name = name.trim();
+                let (name, negate) = match name.strip_prefix('!') {
+                    Some(name) => (name, true),
+                    None => (name, false),
+                };
+                let index = *self
+                    .names
+                    .get(name)
+                    .ok_or(format!("unknown condition {}", name))?;
+                conditions.push((index, negate));
+            }
+            line = &line[end + 1..];
+        }
+        let end = line.find('>').ok_or("missing tag")?;
+        let tag = &line[..end + 1];
+        let args: Vec<&str> = line[end + 1..].split(',').map(|arg| arg.trim()).collect();
+        match tag {
+            "<scale>" => self.scale = parse_dec(args[0])?.max(1) as usize,
+            "<img>" => {
+                let image = load_image(dir, args[0])?;
+                self.images.push(image);
+            }
+            "<condition>" => {
+                let condition = parse_condition(&args, chr)?;
+                self.names
+                    .insert(args[0].to_string(), self.conditions.len());
+                self.conditions.push(condition);
+            }
+            "<tile>" => {
+                if args.len() < 6 {
+                    return Err("too few fields".to_string());
+                }
+                let image = parse_dec(args[0])? as usize;
+                if image >= self.images.len() {
+                    return Err(format!("no image {}", image));
+                }
+                let tile = parse_tile(args[1], chr)?;
+                let palette = parse_hex(args[2])? as u32;
+                let rule = Rule {
+                    conditions,
+                    image,
+                    x: parse_dec(args[3])? as usize,
+                    y: parse_dec(args[4])? as usize,
+                    brightness: args[5].parse::<f32>().map_err(|err| err.to_string())?,
+                };
+                if args.get(6) == Some(&"Y") {
+                    self.defaults.entry(tile).or_default().push(rule);
+                } else {
+                    self.tiles.entry((tile, palette)).or_default().push(rule);
+                }
+            }
+            "<background>" => {
+                let image = load_image(dir, args[0])?;
+                self.images.push(image);
+                let brightness = match args.get(1) {
+                    Some(value) => value.parse::<f32>().map_err(|err| err.to_string())?,
+                    None => 1.0,
+                };
+                self.backgrounds.push(Rule {
+                    conditions,
+                    image: self.images.len() - 1,
+                    x: 0,
+                    y: 0,
+                    brightness,
+                });
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+    // Draws the frame at `scale` times the size from the tile sources the
+    // PPU recorded. Pixels without a replacement keep their NES colour.
+    pub fn render(&mut self, ppu: &mut ppu::Ppu, ram: &[u8], out: &mut Vec<u8>) {
+        let scale = self.scale;
+        let width = 256 * scale;
+        out.resize(width * 240 * scale * 3, 0);
+        self.frame += 1;
+
+        let mut colors = [[0u8; 4]; 8];
+        let mut keys = [0u32; 8];
+        for palette in 0..8 {
+            for i in 0..4 {
+                colors[palette][i] = if i == 0 {
+                    ppu.peek(0x3f00)
+                } else {
+                    ppu.peek(0x3f00 + palette * 4 + i)
+                };
+                keys[palette] = (keys[palette] << 8) | colors[palette][i] as u32;
+            }
+        }
+        let backdrop = ppu.rgb(colors[0][0]);
+        let sources = std::mem::take(&mut ppu.sources);
+        if sources.len() != 256 * 240 {
+            ppu.sources = sources;
+            return;
+        }
+        let frame = Frame {
+            sources: &sources,
+            ram,
+            keys,
+        };
+        let background = self
+            .backgrounds
+            .iter()
+            .find(|rule| self.matches(rule, &frame, &TilePixel::new(), false, 0, 0));
+
+        for y in 0..240 {
+            for x in 0..256 {
+                let i = y * 256 + x;
+                let source = sources[i];
+                let original = (
+                    ppu.imgdata[i * 3],
+                    ppu.imgdata[i * 3 + 1],
+                    ppu.imgdata[i * 3 + 2],
+                );
+                let front = source.sprite.value != 0
+                    && ((source.sprite.attr & 0x20) == 0 || source.bg.value == 0);
+                let layers = if front {
+                    [(source.sprite, true), (source.bg, false)]
+                } else {
+                    [(source.bg, false), (source.sprite, true)]
+                };
+                let rules = layers.map(|(layer, sprite)| {
+                    if layer.value == 0 {
+                        return None;
+                    }
+                    return self.find_rule(&layer, &frame, sprite, x, y);
+                });
+
+                for sy in 0..scale {
+                    for sx in 0..scale {
+                        let mut color = None;
+                        let mut covered = false;
+                        for k in 0..2 {
+                            let (layer, _) = layers[k];
+                            if layer.value == 0 {
+                                continue;
+                            }
+                            if let Some(rule) = rules[k] {
+                                let px = layer_coord(layer.x, layer.attr & 0x40, sx, scale);
+                                let py = layer_coord(layer.y, layer.attr & 0x80, sy, scale);
+                                color = self.sample(rule, rule.x + px, rule.y + py);
+                            } else if !covered {
+                                color = Some(original);
+                            } else {
+                                let palette = layer.palette as usize;
+                                color = Some(ppu.rgb(colors[palette][layer.value as usize]));
+                            }
+                            if color.is_some() {
+                                break;
+                            }
+                            covered = true;
+                        }
+                        if color.is_none() {
+                            if let Some(rule) = background {
+                                color = self.sample(rule, x * scale + sx, y * scale + sy);
+                            }
+                        }
+                        let (r, g, b) = match color {
+                            Some(color) => color,
+                            None if covered => backdrop,
+                            None => original,
+                        };
+                        let o = ((y * scale + sy) * width + x * scale + sx) * 3;
+                        out[o] = r;
+                        out[o + 1] = g;
+                        out[o + 2] = b;
+                    }
+                }
+            }
+        }
+        ppu.sources = sources;
+    }
+    fn find_rule(
+        &self,
+        layer: &TilePixel,
+        frame: &Frame,
+        sprite: bool,
+        x: usize,
+        y: usize,
+    ) -> Option<&Rule> {
+        let key = frame.keys[layer.palette as usize];
+        let exact = self.tiles.get(&(layer.tile, key)).into_iter().flatten();
+        let default = self.defaults.get(&layer.tile).into_iter().flatten();
+        return exact
+            .chain(default)
+            .find(|rule| self.matches(rule, frame, layer, sprite, x, y));
+    }
+    fn matches(
+        &self,
+        rule: &Rule,
+        frame: &Frame,
+        layer: &TilePixel,
+        sprite: bool,
+        x: usize,
+        y: usize,
+    ) -> bool {
+        return rule.conditions.iter().all(|&(index, negate)| {
+            self.check(&self.conditions[index], frame, layer, sprite, x, y) != negate
+        });
+    }
+    fn check(
+        &self,
+        condition: &Condition,
+        frame: &Frame,
+        layer: &TilePixel,
+        sprite: bool,
+        x: usize,
+        y: usize,
+    ) -> bool {
+        match condition {
+            Condition::HMirror => return sprite && (layer.attr & 0x40) != 0,
+            Condition::VMirror => return sprite && (layer.attr & 0x80) != 0,
+            Condition::BgPriority => return sprite && (layer.attr & 0x20) != 0,
+            Condition::Memory {
+                addr,
+                op,
+                operand,
+                constant,
+                mask,
+            } => {
+                let value = read_ram(frame.ram, *addr) & mask;
+                let other = if *constant {
+                    *operand as u8
+                } else {
+                    read_ram(frame.ram, *operand)
+                } & mask;
+                return compare(op, value, other);
+            }
+            Condition::FrameRange { divisor, compare } => {
+                return self.frame % (*divisor).max(1) >= *compare;
+            }
+            Condition::Tile {
+                sprite: on_sprite,
+                nearby,
+                x: cx,
+                y: cy,
+                tile,
+                palette,
+            } => {
+                let (mut tx, mut ty) = (*cx, *cy);
+                if *nearby {
+                    // Relative to the top-left corner of this tile on screen.
+                    let column = if (layer.attr & 0x40) != 0 {
+                        7 - layer.x
+                    } else {
+                        layer.x
+                    };
+                    let row = if (layer.attr & 0x80) != 0 {
+                        7 - layer.y
+                    } else {
+                        layer.y
+                    };
+                    tx += x as isize - column as isize;
+                    ty += y as isize - row as isize;
+                }
+                if !(0..256).contains(&tx) || !(0..240).contains(&ty) {
+                    return false;
+                }
+                let source = &frame.sources[ty as usize * 256 + tx as usize];
+                let other = if *on_sprite {
+                    &source.sprite
+                } else {
+                    &source.bg
+                };
+                if *on_sprite && other.value == 0 {
+                    return false;
+                }
+                return other.tile == *tile && frame.keys[other.palette as usize] == *palette;
+            }
+        }
+    }
+    fn sample(&self, rule: &Rule, x: usize, y: usize) -> Option<(u8, u8, u8)> {
+        let image = &self.images[rule.image];
+        if x >= image.width || y >= image.height {
+            return None;
+        }
+        let i = (y * image.width + x) * 4;
+        if image.rgba[i + 3] == 0 {
+            return None;
+        }
+        let bright = |c: u8| (c as f32 * rule.brightness).min(255.0) as u8;
+        return Some((
+            bright(image.rgba[i]),
+            bright(image.rgba[i + 1]),
+            bright(image.rgba[i + 2]),
+        ));
+    }
+}
+
+// Position in the HD tile of sub-pixel `sub` of tile column or row `pos`.
+fn layer_coord(pos: u8, flip: u8, sub: usize, scale: usize) -> usize {
+    let sub = if flip != 0 { scale - 1 - sub } else { sub };
+    return pos as usize * scale + sub;
+}
+
+fn load_image(dir: &str, name: &str) -> Result<Image, String> {
+    let path = Path::new(dir).join(name);
+    let (width, height, rgba) = image::read_png(&path.to_string_lossy())?;
+    return Ok(Image {
+        width: width as usize,
+        height: height as usize,
+        rgba,
+    });
+}
+
+fn parse_condition(args: &[&str], chr: &[u8]) -> Result<Condition, String> {
+    if args.len() < 2 {
+        return Err("too few fields".to_string());
+    }
+    let arg = |i: usize| -> Result<&str, String> {
+        return args.get(i).copied().ok_or("too few fields".to_string());
+    };
+    let condition = match args[1] {
+        "hmirror" => Condition::HMirror,
+        "vmirror" => Condition::VMirror,
+        "bgpriority" => Condition::BgPriority,
+        "memoryCheck" | "memoryCheckConstant" => Condition::Memory {
+            addr: parse_hex(arg(2)?)?,
+            op: arg(3)?.to_string(),
+            operand: parse_hex(arg(4)?)?,
+            constant: args[1] == "memoryCheckConstant",
+            mask: match args.get(5) {
+                Some(mask) => parse_hex(mask)? as u8,
+                None => 0xff,
+            },
+        },
+        "frameRange" => Condition::FrameRange {
+            divisor: parse_dec(arg(2)?)? as usize,
+            compare: parse_dec(arg(3)?)? as usize,
+        },
+        "tileAtPosition" | "spriteAtPosition" | "tileNearby" | "spriteNearby" => Condition::Tile {
+            sprite: args[1].starts_with("sprite"),
+            nearby: args[1].ends_with("Nearby"),
+            x: parse_dec(arg(2)?)?,
+            y: parse_dec(arg(3)?)?,
+            tile: parse_tile(arg(4)?, chr)?,
+            palette: parse_hex(arg(5)?)? as u32,
+        },
+        kind => return Err(format!("unsupported condition {}", kind)),
+    };
+    return Ok(condition);
+}
+
+fn parse_tile(text: &str, chr: &[u8]) -> Result<[u8; 16], String> {
+    let mut tile = [0; 16];
+    if text.len() == 32 {
+        for i in 0..16 {
+            tile[i] = parse_hex(&text[i * 2..i * 2 + 2])? as u8;
+        }
+        return Ok(tile);
+    }
+    let offset = parse_hex(text)? * 16;
+    if offset + 16 > chr.len() {
+        return Err(format!("tile {} is outside CHR ROM", text));
+    }
+    tile.copy_from_slice(&chr[offset..offset + 16]);
+    return Ok(tile);
+}
+
+fn parse_hex(text: &str) -> Result<usize, String> {
+    return usize::from_str_radix(text, 16).map_err(|err| err.to_string());
+}
+
+fn parse_dec(text: &str) -> Result<isize, String> {
+    return text.parse::<isize>().map_err(|err| err.to_string());
+}
+
+fn read_ram(ram: &[u8], addr: usize) -> u8 {
+    if addr < 0x2000 {
+        return ram[addr & 0x07ff];
+    }
+    return 0;
+}
+
+fn compare(op: &str, a: u8, b: u8) -> bool {
+    match op {
+        "==" => a == b,
+        "!=" => a != b,
+        ">" => a > b,
+        "<" => a < b,
+        ">=" => a >= b,
+        "<=" => a <= b,
+        _ => false,
+    }
+}
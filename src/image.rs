@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+// PNG output for screenshots and debug dumps.
+pub fn write_png(path: &str, width: u32, height: u32, rgb: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer
+        .write_image_data(rgb)
+        .map_err(|err| err.to_string())?;
+    return Ok(());
+}
+
+// 8-bit indexed PNG, with optional tEXt chunks.
+pub fn write_indexed_png(
+    path: &str,
+    width: u32,
+    height: u32,
+    indices: &[u8],
+    palette: &[(u8, u8, u8)],
+    text: &[(&str, String)],
+) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect::<Vec<u8>>(),
+    );
+    for (keyword, value) in text {
+        encoder
+            .add_text_chunk(keyword.to_string(), value.clone())
+            .map_err(|err| err.to_string())?;
+    }
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer
+        .write_image_data(indices)
+        .map_err(|err| err.to_string())?;
+    return Ok(());
+}
+
+// Decodes any PNG to 8-bit RGBA.
+pub fn read_png(path: &str) -> Result<(u32, u32, Vec<u8>), String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|err| err.to_string())?;
+    let pixels = &buf[..info.buffer_size()];
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels
+            .chunks(3)
+            .flat_map(|c| [c[0], c[1], c[2], 0xff])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks(2)
+            .flat_map(|c| [c[0], c[0], c[0], c[1]])
+            .collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&c| [c, c, c, 0xff]).collect(),
+        png::ColorType::Indexed => return Err("unexpanded palette image".to_string()),
+    };
+    return Ok((info.width, info.height, rgba));
+}
@@ -30,7 +30,7 @@ impl Irq {
     pub fn check_interrupt(&mut self, cpu: &cpu::Cpu) -> String {
         if (self.nmi) {
             return "nmi".to_string();
-        } else if cpu.interrupt && self.irq {
+        } else if !cpu.interrupt && self.irq {
             return "irq".to_string();
         } else {
             return "".to_string();
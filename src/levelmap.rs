@@ -0,0 +1,108 @@
+use crate::image;
+use crate::ppu;
+use std::collections::HashMap;
+
+const BLOCK: i64 = 256;
+const EMPTY: u8 = 0xff;
+
+// Stitches the background of every frame into one map of the level.
+//
+// The scroll origin from t and fine x is followed from frame to frame; the
+// step between two frames is taken as the shortest way around the 512x480
+// nametable space, so the map position keeps growing past the wrap. Pixels
+// are stored as palette RAM colours in 256x256 blocks; where frames overlap
+// the newest capture replaces the older one instead of adding to the map.
+// Frames with the background switched off are skipped.
+pub struct LevelMap {
+    blocks: HashMap<(i64, i64), Vec<u8>>,
+    scroll: Option<(usize, usize)>,
+    x: i64,
+    y: i64,
+    bounds: Option<(i64, i64, i64, i64)>,
+}
+impl LevelMap {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            scroll: None,
+            x: 0,
+            y: 0,
+            bounds: None,
+        }
+    }
+    pub fn capture(&mut self, ppu: &mut ppu::Ppu) {
+        if !ppu.is_screen_enable() {
+            return;
+        }
+        let (sx, sy) = ppu.scroll_origin();
+        if let Some((last_x, last_y)) = self.scroll {
+            self.x += wrap_delta(sx as i64 - last_x as i64, 512);
+            self.y += wrap_delta(sy as i64 - last_y as i64, 480);
+        }
+        self.scroll = Some((sx, sy));
+
+        for y in 0..240 {
+            for x in 0..256 {
+                let mx = self.x + x as i64;
+                let my = self.y + y as i64;
+                let key = (mx.div_euclid(BLOCK), my.div_euclid(BLOCK));
+                let i = (my.rem_euclid(BLOCK) * BLOCK + mx.rem_euclid(BLOCK)) as usize;
+                let block = self
+                    .blocks
+                    .entry(key)
+                    .or_insert_with(|| vec![EMPTY; (BLOCK * BLOCK) as usize]);
+                block[i] = ppu.peek_background(sx + x, sy + y);
+            }
+        }
+        let (left, top, right, bottom) =
+            self.bounds
+                .unwrap_or((self.x, self.y, self.x + 256, self.y + 240));
+        self.bounds = Some((
+            left.min(self.x),
+            top.min(self.y),
+            right.max(self.x + 256),
+            bottom.max(self.y + 240),
+        ));
+    }
+    // Colours go through the PPU's current palette table. Parts of the map
+    // never seen are left black.
+    pub fn save_png(&self, ppu: &mut ppu::Ppu, path: &str) -> Result<(), String> {
+        let (left, top, right, bottom) = match self.bounds {
+            Some(bounds) => bounds,
+            None => return Err("nothing captured".to_string()),
+        };
+        let width = (right - left) as usize;
+        let height = (bottom - top) as usize;
+        let mut rgb = vec![0; width * height * 3];
+        for y in 0..height {
+            for x in 0..width {
+                let mx = left + x as i64;
+                let my = top + y as i64;
+                let key = (mx.div_euclid(BLOCK), my.div_euclid(BLOCK));
+                let color = match self.blocks.get(&key) {
+                    Some(block) => {
+                        block[(my.rem_euclid(BLOCK) * BLOCK + mx.rem_euclid(BLOCK)) as usize]
+                    }
+                    None => EMPTY,
+                };
+                if color == EMPTY {
+                    continue;
+                }
+                let (r, g, b) = ppu.rgb(color);
+                let i = (y * width + x) * 3;
+                rgb[i] = r;
+                rgb[i + 1] = g;
+                rgb[i + 2] = b;
+            }
+        }
+        return image::write_png(path, width as u32, height as u32, &rgb);
+    }
+}
+
+fn wrap_delta(delta: i64, size: i64) -> i64 {
+    let delta = delta.rem_euclid(size);
+    if delta > size / 2 {
+        return delta - size;
+    }
+    return delta;
+}
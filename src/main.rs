@@ -1,80 +1,160 @@
 #![allow(warnings, unused, dead_code)]
 use std::env;
 use std::fs;
+pub mod audio;
+pub mod config;
 pub mod cpu;
+pub mod crt;
 pub mod dma;
+pub mod eeprom;
+pub mod fds;
+pub mod fds_sound;
+pub mod filter;
+pub mod gifclip;
+pub mod hdpack;
+pub mod image;
 pub mod io;
 pub mod irq;
+pub mod levelmap;
 pub mod mapper;
 pub mod mapper0;
+pub mod mapper16;
+pub mod mapper21;
+pub mod mapper9;
 pub mod mem;
 pub mod nes;
 pub mod nestest;
+pub mod ntsc;
+pub mod palette;
 pub mod ppu;
+pub mod recorder;
 pub mod rom;
+pub mod scaler;
+#[cfg(unix)]
+pub mod terminal;
+pub mod viewer;
 
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::Color;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::PixelFormatEnum;
+#[cfg(feature = "sdl")]
 use sdl2::render::Canvas;
+#[cfg(feature = "sdl")]
 use sdl2::render::Texture;
+#[cfg(feature = "sdl")]
 use sdl2::render::TextureCreator;
+#[cfg(feature = "sdl")]
 use sdl2::video::Window;
+#[cfg(feature = "sdl")]
 use sdl2::video::WindowContext;
+#[cfg(feature = "sdl")]
+use sdl2::AudioSubsystem;
+#[cfg(feature = "sdl")]
 use sdl2::EventPump;
 
-const WIDTH: u32 = 256;
-const HEIGHT: u32 = 224;
 const SCALE: u32 = 2;
+// Three rows per line and one phosphor triad per pixel column.
+const CRT_SCALE: u32 = 3;
 
 #[macro_use]
 extern crate bitflags;
 
 fn main() {
-    let (event_pump, canvas) = create_window();
+    let args: Vec<String> = env::args().collect();
+    let config = config::Config::from_args(&args[1..]);
 
     let mut nes = nes::Nes::new();
     nes.init();
 
     let cputest = false;
-    let mut filename = "";
+    let mut filename = config.filename.as_str();
     if cputest {
         filename = "nestest.nes";
-    } else {
-        filename = "sm.nes";
     }
 
-    match fs::read(filename) {
-        Result::Ok(buf) => {
-            nes.set_rom(buf);
-        }
+    let buf = match fs::read(filename) {
+        Result::Ok(buf) => buf,
         Result::Err(err) => {
             eprintln!("Cannot open .nes file: {}", filename);
             filename = "j.nes";
             match fs::read(filename) {
-                Result::Ok(buf) => {
-                    nes.set_rom(buf);
-                }
+                Result::Ok(buf) => buf,
                 Result::Err(err) => {
                     eprintln!("Cannot open .nes file: {}", filename);
                     panic!("{}", err);
                 }
             }
         }
+    };
+    if let Result::Err(err) = nes.set_rom(buf, filename) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    match config.palette.as_str() {
+        "default" => {}
+        "ntsc" => nes.set_palette(palette::generate(&config.ntsc)),
+        path => match palette::load_pal(path) {
+            Result::Ok(table) => nes.set_palette(table),
+            Result::Err(err) => eprintln!("Cannot load palette {}: {}", path, err),
+        },
+    }
+    nes.set_sprite_limit(config.sprite_limit);
+    if !config.hd_pack.is_empty() {
+        nes.set_hd_pack(&config.hd_pack);
+    }
+    if let Some(region) = config.region {
+        nes.set_region(region);
+    }
+    match config.frontend.as_str() {
+        "ansi" | "sixel" => start_terminal(&mut nes, &config),
+        frontend => {
+            if frontend != "sdl" {
+                eprintln!("Unknown frontend: {}", frontend);
+            }
+            start_window(&mut nes, cputest, &config);
+        }
     }
-    nes.start(cputest, event_pump, canvas);
 }
-fn create_window() -> (EventPump, Canvas<Window>) {
+#[cfg(feature = "sdl")]
+fn start_window(nes: &mut nes::Nes, cputest: bool, config: &config::Config) {
+    let (event_pump, canvas, audio) = create_window(config);
+    nes.start(cputest, event_pump, canvas, audio, config);
+}
+#[cfg(not(feature = "sdl"))]
+fn start_window(nes: &mut nes::Nes, cputest: bool, config: &config::Config) {
+    eprintln!("Built without SDL; use --frontend ansi or sixel");
+}
+#[cfg(unix)]
+fn start_terminal(nes: &mut nes::Nes, config: &config::Config) {
+    let mode = if config.frontend == "sixel" {
+        terminal::Mode::SIXEL
+    } else {
+        terminal::Mode::ANSI
+    };
+    nes.start_terminal(mode, config);
+}
+#[cfg(not(unix))]
+fn start_terminal(nes: &mut nes::Nes, config: &config::Config) {
+    eprintln!("The terminal frontend needs a Unix terminal");
+}
+#[cfg(feature = "sdl")]
+fn create_window(config: &config::Config) -> (EventPump, Canvas<Window>, Option<AudioSubsystem>) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let scale = if config.crt > 0.0 { CRT_SCALE } else { SCALE };
     let window = video_subsystem
-        .window("", (WIDTH * SCALE) as u32, (HEIGHT * SCALE) as u32)
+        .window("", config.width() * scale, config.height() * scale)
         .position_centered()
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
-    canvas.set_scale(SCALE as f32, SCALE as f32).unwrap();
+    canvas.set_scale(scale as f32, scale as f32).unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    (event_pump, canvas)
+    let audio = sdl_context.audio().ok();
+    (event_pump, canvas, audio)
 }
@@ -1,9 +1,26 @@
+use crate::fds;
+use crate::io;
+use crate::irq;
+use crate::mapper0;
+use crate::mapper16;
+use crate::mapper21;
+use crate::mapper9;
+use crate::ppu;
+use crate::rom;
+
 pub struct Base {
     mapper_reg: Vec<u8>,
 }
 pub trait MapperBase {
+    fn rom(&mut self) -> &mut rom::Rom;
+    fn ppu(&mut self) -> &mut ppu::Ppu;
+    fn io(&mut self) -> &mut io::Io;
+    fn set_rom(&mut self, buf: Vec<u8>) -> Result<(), String>;
+
     fn init(&mut self) {
         println!("mapperbase init");
+        self.ppu().init();
+        self.io().init();
     }
     fn read_low(&mut self, addr: u16) -> u8 {
         return 0x00;
@@ -15,19 +32,58 @@ pub trait MapperBase {
     fn write_ppudata(&mut self) {}
     fn build_bgline(&mut self) {}
     fn build_spriteline(&mut self) {}
-    fn read_sram(&mut self) -> u8 {
-        return 0x00;
+    fn read_sram(&mut self, addr: u16) -> u8 {
+        return self.rom().srams[(addr & 0x1fff) as usize];
+    }
+    fn write_sram(&mut self, addr: u16, data: u8) {
+        self.rom().srams[(addr & 0x1fff) as usize] = data;
     }
-    fn write_sram(&mut self) {}
     fn write(&mut self, addr: u16, data: u8) {}
     fn hsync(&mut self) {}
-    fn cpusync(&mut self) {}
+    fn cpusync(&mut self, cpuclock: usize, irq: &mut irq::Irq) {}
+    // Only called for mappers that set `ppu().watch_patterns`, right after
+    // the PPU reads `addr` from the pattern tables.
+    fn pattern_fetch(&mut self, addr: usize) {}
+    fn run_ppu(&mut self, cpuclock: usize) {
+        self.ppu().add_cycles(cpuclock);
+        while let Some(addr) = self.ppu().run_dots() {
+            self.pattern_fetch(addr);
+        }
+    }
     fn setirq(&mut self) {}
     fn clearirq(&mut self) {}
-    fn out_exsound(&mut self) -> u8 {
-        return 0x00;
+    fn out_exsound(&mut self) -> i32 {
+        return 0;
     }
     fn exsound_sync(&mut self) {}
     fn getstate(&mut self) {}
     fn setstate(&mut self) {}
+    fn switch_disk(&mut self) {}
+    fn save(&mut self) {}
+}
+
+pub fn new_mapper(
+    buf: &Vec<u8>,
+    rom: rom::Rom,
+    ppu: ppu::Ppu,
+    io: io::Io,
+) -> Result<Box<dyn MapperBase>, String> {
+    if fds::is_fds_image(buf) {
+        return Ok(Box::new(fds::Fds::new(rom, ppu, io)));
+    }
+    if buf.len() < 16 || buf[0..4] != [0x4e, 0x45, 0x53, 0x1a] {
+        return Err("Invalid *.nes file: no iNES header".to_string());
+    }
+    let mapper_number = (buf[6] >> 4) | (buf[7] & 0xf0);
+    let mapper: Box<dyn MapperBase> = match mapper_number {
+        0 => Box::new(mapper0::Mapper0::new(rom, ppu, io)),
+        9 | 10 => Box::new(mapper9::Mapper9::new(rom, ppu, io)),
+        16 | 153 | 157 | 159 => Box::new(mapper16::Mapper16::new(rom, ppu, io)),
+        21 | 22 | 23 | 25 => Box::new(mapper21::Mapper21::new(rom, ppu, io)),
+        _ => {
+            println!("unsupported mapper {}, using mapper 0", mapper_number);
+            Box::new(mapper0::Mapper0::new(rom, ppu, io))
+        }
+    };
+    return Ok(mapper);
 }
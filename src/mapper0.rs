@@ -8,26 +8,29 @@ pub struct Mapper0 {
     pub ppu: ppu::Ppu,
     pub io: io::Io,
 }
-impl mapper::MapperBase for mapper::Base {}
-impl mapper::MapperBase for Mapper0 {}
-impl Mapper0 {
-    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
-        Self { rom, ppu, io }
+impl mapper::MapperBase for Mapper0 {
+    fn rom(&mut self) -> &mut rom::Rom {
+        &mut self.rom
     }
-
-    pub fn set_rom(&mut self, mut buf: Vec<u8>) {
+    fn ppu(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+    fn io(&mut self) -> &mut io::Io {
+        &mut self.io
+    }
+    fn set_rom(&mut self, mut buf: Vec<u8>) -> Result<(), String> {
         println!("Mapper0 set_rom");
         self.rom.set_rom(buf);
         self.rom.set_prgrom_page(0, 0);
         self.rom.set_prgrom_page(1, self.rom.prg_rom_page_count - 1);
         self.ppu.set_chr_rom_page(0, &mut self.rom);
         self.ppu.start(&mut self.rom);
+        return Ok(());
     }
-    pub fn init(&mut self) {
-        println!("mapper init");
-        // self.rom.init();
-        self.ppu.init();
-        self.io.init();
+}
+impl Mapper0 {
+    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
+        Self { rom, ppu, io }
     }
     pub fn render(&mut self) {}
 }
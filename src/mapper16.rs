@@ -0,0 +1,221 @@
+use crate::eeprom;
+use crate::io;
+use crate::irq;
+use crate::mapper;
+use crate::ppu;
+use crate::rom;
+use std::fs;
+use std::path::Path;
+
+// Bandai FCG-1/FCG-2 and LZ93D50 boards (mappers 16, 153, 157 and 159).
+//
+// FCG-1/2 decode their registers at $6000-$7FFF and load the IRQ counter
+// directly; the LZ93D50 moved them to $8000-$FFFF and added a reload latch.
+// Mapper 16 without a submapper accepts both and picks the IRQ behaviour
+// from whichever range the game writes to.
+pub struct Mapper16 {
+    pub rom: rom::Rom,
+    pub ppu: ppu::Ppu,
+    pub io: io::Io,
+
+    regs_low: bool,
+    regs_high: bool,
+    eeprom: Option<eeprom::Eeprom>,
+    prg_ram_enabled: bool,
+
+    chr_regs: Vec<u8>,
+    prg_reg: usize,
+    prg_outer: usize,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_reload: u16,
+    irq_pending: bool,
+}
+impl mapper::MapperBase for Mapper16 {
+    fn rom(&mut self) -> &mut rom::Rom {
+        &mut self.rom
+    }
+    fn ppu(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+    fn io(&mut self) -> &mut io::Io {
+        &mut self.io
+    }
+    fn set_rom(&mut self, mut buf: Vec<u8>) -> Result<(), String> {
+        println!("Mapper16 set_rom");
+        self.rom.set_rom(buf);
+
+        let (regs_low, regs_high, eeprom) = match (self.rom.mapper_number, self.rom.submapper) {
+            (16, 4) => (true, false, None),
+            (16, 5) => (false, true, Some(eeprom::EepromKind::X24C02)),
+            (16, _) => (true, true, Some(eeprom::EepromKind::X24C02)),
+            (153, _) => (false, true, None),
+            (157, _) => (false, true, Some(eeprom::EepromKind::X24C02)),
+            _ => (false, true, Some(eeprom::EepromKind::X24C01)),
+        };
+        self.regs_low = regs_low;
+        self.regs_high = regs_high;
+        self.eeprom = eeprom.map(|kind| eeprom::Eeprom::new(kind));
+        self.load_save();
+
+        self.update_prg();
+        self.ppu.set_chr_rom_page(0, &mut self.rom);
+        self.ppu.start(&mut self.rom);
+        return Ok(());
+    }
+    fn read_sram(&mut self, addr: u16) -> u8 {
+        if let Some(eeprom) = self.eeprom.as_mut() {
+            return (eeprom.read() << 4) | ((addr >> 8) as u8 & 0xe7);
+        }
+        if self.rom.mapper_number == 153 && self.prg_ram_enabled {
+            return self.rom.srams[(addr & 0x1fff) as usize];
+        }
+        return (addr >> 8) as u8;
+    }
+    fn write_sram(&mut self, addr: u16, data: u8) {
+        if self.regs_low {
+            self.write_reg(addr, data, false);
+        } else if self.rom.mapper_number == 153 && self.prg_ram_enabled {
+            self.rom.srams[(addr & 0x1fff) as usize] = data;
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        if self.regs_high {
+            self.write_reg(addr, data, true);
+        }
+    }
+    fn cpusync(&mut self, cpuclock: usize, irq: &mut irq::Irq) {
+        if self.irq_enabled {
+            for _ in 0..cpuclock {
+                // The counter is checked before it is decremented.
+                if self.irq_counter == 0 {
+                    self.irq_pending = true;
+                }
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+            }
+        }
+        irq.set_irq(self.irq_pending);
+    }
+    fn save(&mut self) {
+        let buf = match self.eeprom.as_mut() {
+            Some(eeprom) if eeprom.modified => {
+                eeprom.modified = false;
+                eeprom.data.to_vec()
+            }
+            None if self.rom.mapper_number == 153 && self.rom.sram_enable => {
+                self.rom.srams.to_vec()
+            }
+            _ => return,
+        };
+        let path = self.save_path();
+        if let Result::Err(err) = fs::write(&path, buf) {
+            eprintln!("Cannot write save file: {} ({})", path, err);
+        }
+    }
+}
+impl Mapper16 {
+    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
+        Self {
+            rom,
+            ppu,
+            io,
+
+            regs_low: false,
+            regs_high: true,
+            eeprom: None,
+            prg_ram_enabled: false,
+
+            chr_regs: vec![0; 8],
+            prg_reg: 0,
+            prg_outer: 0,
+
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_reload: 0,
+            irq_pending: false,
+        }
+    }
+    fn save_path(&self) -> String {
+        let path = Path::new(&self.rom.filename).with_extension("sav");
+        return path.to_string_lossy().to_string();
+    }
+    fn load_save(&mut self) {
+        let buf = match fs::read(self.save_path()) {
+            Result::Ok(buf) => buf,
+            Result::Err(_) => return,
+        };
+        println!("loading save from {}", self.save_path());
+        if let Some(eeprom) = self.eeprom.as_mut() {
+            eeprom.load(&buf);
+        } else {
+            let len = buf.len().min(self.rom.srams.len());
+            self.rom.srams[..len].copy_from_slice(&buf[..len]);
+        }
+    }
+    fn write_reg(&mut self, addr: u16, data: u8, lz93d50: bool) {
+        match addr & 0x000f {
+            0x00..=0x07 => {
+                let bank = (addr & 0x07) as usize;
+                self.chr_regs[bank] = data;
+                if self.rom.mapper_number == 153 {
+                    // CHR is RAM on this board; the registers select the 256K PRG half.
+                    self.prg_outer = (data & 0x01) as usize;
+                    self.update_prg();
+                } else {
+                    self.ppu
+                        .set_chr_rom_data1k(bank as isize, data as isize, &mut self.rom);
+                }
+            }
+            0x08 => {
+                self.prg_reg = (data & 0x0f) as usize;
+                self.update_prg();
+            }
+            0x09 => {
+                self.set_mirroring(data & 0x03);
+            }
+            0x0a => {
+                self.irq_enabled = (data & 0x01) != 0;
+                if lz93d50 {
+                    self.irq_counter = self.irq_reload;
+                }
+                self.irq_pending = false;
+            }
+            0x0b => {
+                if lz93d50 {
+                    self.irq_reload = (self.irq_reload & 0xff00) | data as u16;
+                } else {
+                    self.irq_counter = (self.irq_counter & 0xff00) | data as u16;
+                }
+            }
+            0x0c => {
+                if lz93d50 {
+                    self.irq_reload = (self.irq_reload & 0x00ff) | ((data as u16) << 8);
+                } else {
+                    self.irq_counter = (self.irq_counter & 0x00ff) | ((data as u16) << 8);
+                }
+            }
+            0x0d => {
+                if self.rom.mapper_number == 153 {
+                    self.prg_ram_enabled = (data & 0x20) != 0;
+                } else if let Some(eeprom) = self.eeprom.as_mut() {
+                    eeprom.write((data >> 5) & 0x01, (data >> 6) & 0x01);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn update_prg(&mut self) {
+        let outer = self.prg_outer << 4;
+        self.rom.set_prgrom_page(0, outer | self.prg_reg);
+        self.rom.set_prgrom_page(1, outer | 0x0f);
+    }
+    fn set_mirroring(&mut self, mirroring: u8) {
+        self.ppu.set_mirroring(match mirroring {
+            0 => rom::Mirroring::VERTICAL,
+            1 => rom::Mirroring::HORIZONTAL,
+            2 => rom::Mirroring::SINGLE_SCREEN_LOWER,
+            _ => rom::Mirroring::SINGLE_SCREEN_UPPER,
+        });
+    }
+}
@@ -0,0 +1,254 @@
+use crate::io;
+use crate::irq;
+use crate::mapper;
+use crate::ppu;
+use crate::rom;
+
+// Konami VRC2/VRC4 (mappers 21, 22, 23 and 25).
+//
+// Every board feeds two CPU address lines into the chip's register select
+// pins, and which lines those are differs per board. NES 2.0 submappers name
+// the board; without one, both candidate lines are ORed together, which works
+// because games only ever touch the registers with the other line low.
+pub struct Mapper21 {
+    pub rom: rom::Rom,
+    pub ppu: ppu::Ppu,
+    pub io: io::Io,
+
+    line0: u16,
+    line1: u16,
+    vrc2: bool,
+    chr_shift: usize,
+
+    prg_regs: Vec<usize>,
+    prg_mode: bool,
+    chr_regs: Vec<usize>,
+    microwire_latch: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_prescaler: isize,
+    irq_enable: bool,
+    irq_enable_after_ack: bool,
+    irq_cycle_mode: bool,
+    irq_pending: bool,
+}
+impl mapper::MapperBase for Mapper21 {
+    fn rom(&mut self) -> &mut rom::Rom {
+        &mut self.rom
+    }
+    fn ppu(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+    fn io(&mut self) -> &mut io::Io {
+        &mut self.io
+    }
+    fn set_rom(&mut self, mut buf: Vec<u8>) -> Result<(), String> {
+        println!("Mapper21 set_rom");
+        self.rom.set_rom(buf);
+        self.set_variant();
+        self.update_prg();
+        self.ppu.set_chr_rom_page(0, &mut self.rom);
+        self.ppu.start(&mut self.rom);
+        return Ok(());
+    }
+    fn read_sram(&mut self, addr: u16) -> u8 {
+        if self.vrc2 {
+            if addr < 0x7000 {
+                return self.microwire_latch | 0x60;
+            }
+            return 0x70;
+        }
+        return self.rom.srams[(addr & 0x1fff) as usize];
+    }
+    fn write_sram(&mut self, addr: u16, data: u8) {
+        if self.vrc2 {
+            if addr < 0x7000 {
+                self.microwire_latch = data & 0x01;
+            }
+            return;
+        }
+        self.rom.srams[(addr & 0x1fff) as usize] = data;
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let addr = self.translate(addr);
+        match addr {
+            0x8000..=0x8003 => {
+                self.prg_regs[0] = (data & 0x1f) as usize;
+                self.update_prg();
+            }
+            0x9000..=0x9001 => {
+                let mirroring = if self.vrc2 { data & 0x01 } else { data & 0x03 };
+                self.set_mirroring(mirroring);
+            }
+            0x9002..=0x9003 => {
+                if !self.vrc2 {
+                    self.prg_mode = (data & 0x02) != 0;
+                    self.update_prg();
+                }
+            }
+            0xa000..=0xa003 => {
+                self.prg_regs[1] = (data & 0x1f) as usize;
+                self.update_prg();
+            }
+            0xb000..=0xefff => {
+                let sub = (addr & 0x03) as usize;
+                let bank = (((addr - 0xb000) >> 12) as usize) * 2 + (sub >> 1);
+                if (sub & 0x01) == 0 {
+                    self.chr_regs[bank] = (self.chr_regs[bank] & 0x1f0) | (data & 0x0f) as usize;
+                } else {
+                    let high = if self.vrc2 { data & 0x0f } else { data & 0x1f };
+                    self.chr_regs[bank] = (self.chr_regs[bank] & 0x0f) | ((high as usize) << 4);
+                }
+                let page = (self.chr_regs[bank] >> self.chr_shift) as isize;
+                self.ppu
+                    .set_chr_rom_data1k(bank as isize, page, &mut self.rom);
+            }
+            0xf000 => {
+                self.irq_latch = (self.irq_latch & 0xf0) | (data & 0x0f);
+            }
+            0xf001 => {
+                self.irq_latch = (self.irq_latch & 0x0f) | ((data & 0x0f) << 4);
+            }
+            0xf002 => {
+                self.irq_enable_after_ack = (data & 0x01) != 0;
+                self.irq_enable = (data & 0x02) != 0;
+                self.irq_cycle_mode = (data & 0x04) != 0;
+                if self.irq_enable {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = 341;
+                }
+                self.irq_pending = false;
+            }
+            0xf003 => {
+                self.irq_enable = self.irq_enable_after_ack;
+                self.irq_pending = false;
+            }
+            _ => {}
+        }
+    }
+    fn cpusync(&mut self, cpuclock: usize, irq: &mut irq::Irq) {
+        if self.vrc2 {
+            return;
+        }
+        for _ in 0..cpuclock {
+            self.clock_irq();
+        }
+        irq.set_irq(self.irq_pending);
+    }
+}
+impl Mapper21 {
+    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
+        Self {
+            rom,
+            ppu,
+            io,
+
+            line0: 0x01,
+            line1: 0x02,
+            vrc2: false,
+            chr_shift: 0,
+
+            prg_regs: vec![0, 1],
+            prg_mode: false,
+            chr_regs: vec![0; 8],
+            microwire_latch: 0,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: 341,
+            irq_enable: false,
+            irq_enable_after_ack: false,
+            irq_cycle_mode: false,
+            irq_pending: false,
+        }
+    }
+    // Without a submapper, mappers 23 and 25 could be VRC2 or VRC4. VRC2
+    // boards have no battery-backed PRG-RAM and at most 128K of CHR, so those
+    // are taken as VRC2; a VRC4 board that happens to fit the same profile
+    // needs an NES 2.0 header with the right submapper.
+    fn set_variant(&mut self) {
+        let submapper = self.rom.submapper;
+        let vrc2_like = !self.rom.sram_enable && self.rom.chr_rom_page_count <= 16;
+        let (line0, line1, vrc2) = match (self.rom.mapper_number, submapper) {
+            (21, 1) => (0x02, 0x04, false),
+            (21, 2) => (0x40, 0x80, false),
+            (21, _) => (0x42, 0x84, false),
+            (22, _) => (0x02, 0x01, true),
+            (23, 1) => (0x01, 0x02, false),
+            (23, 2) => (0x04, 0x08, false),
+            (23, 3) => (0x01, 0x02, true),
+            (23, 0) if vrc2_like => (0x01, 0x02, true),
+            (23, _) => (0x05, 0x0a, false),
+            (25, 1) => (0x02, 0x01, false),
+            (25, 2) => (0x08, 0x04, false),
+            (25, 3) => (0x02, 0x01, true),
+            (25, 0) if vrc2_like => (0x02, 0x01, true),
+            (25, _) => (0x0a, 0x05, false),
+            _ => (0x01, 0x02, false),
+        };
+        self.line0 = line0;
+        self.line1 = line1;
+        self.vrc2 = vrc2;
+        // VRC2a leaves the lowest CHR bank bit unconnected.
+        self.chr_shift = if self.rom.mapper_number == 22 { 1 } else { 0 };
+        println!(
+            "VRC{} mapper {} submapper {}",
+            if vrc2 { 2 } else { 4 },
+            self.rom.mapper_number,
+            submapper
+        );
+    }
+    fn translate(&mut self, addr: u16) -> u16 {
+        let mut sub = 0;
+        if (addr & self.line0) != 0 {
+            sub |= 0x01;
+        }
+        if (addr & self.line1) != 0 {
+            sub |= 0x02;
+        }
+        return (addr & 0xf000) | sub;
+    }
+    fn update_prg(&mut self) {
+        let last = (self.rom.prg_rom_page_count * 2) as isize - 1;
+        if self.prg_mode {
+            self.rom.set_prgrom_page_8k(0, last - 1);
+            self.rom.set_prgrom_page_8k(2, self.prg_regs[0] as isize);
+        } else {
+            self.rom.set_prgrom_page_8k(0, self.prg_regs[0] as isize);
+            self.rom.set_prgrom_page_8k(2, last - 1);
+        }
+        self.rom.set_prgrom_page_8k(1, self.prg_regs[1] as isize);
+        self.rom.set_prgrom_page_8k(3, last);
+    }
+    fn set_mirroring(&mut self, mirroring: u8) {
+        self.ppu.set_mirroring(match mirroring {
+            0 => rom::Mirroring::VERTICAL,
+            1 => rom::Mirroring::HORIZONTAL,
+            2 => rom::Mirroring::SINGLE_SCREEN_LOWER,
+            _ => rom::Mirroring::SINGLE_SCREEN_UPPER,
+        });
+    }
+    fn clock_irq(&mut self) {
+        if !self.irq_enable {
+            return;
+        }
+        if self.irq_cycle_mode {
+            self.clock_irq_counter();
+        } else {
+            self.irq_prescaler -= 3;
+            if self.irq_prescaler <= 0 {
+                self.irq_prescaler += 341;
+                self.clock_irq_counter();
+            }
+        }
+    }
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0xff {
+            self.irq_counter = self.irq_latch;
+            self.irq_pending = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::io;
+use crate::mapper;
+use crate::ppu;
+use crate::rom;
+
+const LATCH_FD: usize = 0;
+const LATCH_FE: usize = 1;
+
+// MMC2/MMC4 CHR latches. Each 4K pattern table half has an FD and an FE
+// bank; the PPU fetching tile $FD or $FE from that half flips which one is
+// mapped, taking effect from the next fetch on.
+struct ChrLatch {
+    exact_low: bool,
+    latch: [usize; 2],
+    banks: [[usize; 2]; 2],
+}
+impl ChrLatch {
+    fn new(exact_low: bool) -> Self {
+        Self {
+            exact_low,
+            latch: [LATCH_FE, LATCH_FE],
+            banks: [[0; 2]; 2],
+        }
+    }
+    // The half whose latch flipped, if any.
+    fn fetch(&mut self, addr: usize) -> Option<usize> {
+        let half = (addr >> 12) & 0x01;
+        let offset = addr & 0x0fff;
+        // MMC2 only reacts to the exact $0FD8/$0FE8 fetch in the low half.
+        let key = if self.exact_low && half == 0 {
+            offset
+        } else {
+            offset & 0x0ff8
+        };
+        let sel = match key {
+            0x0fd8 => LATCH_FD,
+            0x0fe8 => LATCH_FE,
+            _ => return None,
+        };
+        if self.latch[half] == sel {
+            return None;
+        }
+        self.latch[half] = sel;
+        return Some(half);
+    }
+    fn bank(&self, half: usize) -> usize {
+        return self.banks[half][self.latch[half]];
+    }
+}
+
+// MMC2 (mapper 9, Punch-Out!!) and MMC4 (mapper 10, Fire Emblem).
+pub struct Mapper9 {
+    pub rom: rom::Rom,
+    pub ppu: ppu::Ppu,
+    pub io: io::Io,
+
+    mmc4: bool,
+    latch: ChrLatch,
+}
+impl mapper::MapperBase for Mapper9 {
+    fn rom(&mut self) -> &mut rom::Rom {
+        &mut self.rom
+    }
+    fn ppu(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+    fn io(&mut self) -> &mut io::Io {
+        &mut self.io
+    }
+    fn set_rom(&mut self, mut buf: Vec<u8>) -> Result<(), String> {
+        println!("Mapper9 set_rom");
+        self.rom.set_rom(buf);
+        self.mmc4 = self.rom.mapper_number == 10;
+
+        let last = (self.rom.prg_rom_page_count * 2) as isize - 1;
+        if self.mmc4 {
+            self.rom.set_prgrom_page(0, 0);
+            self.rom.set_prgrom_page(1, self.rom.prg_rom_page_count - 1);
+        } else {
+            self.rom.set_prgrom_page_8k(0, 0);
+            self.rom.set_prgrom_page_8k(1, last - 2);
+            self.rom.set_prgrom_page_8k(2, last - 1);
+            self.rom.set_prgrom_page_8k(3, last);
+        }
+
+        self.latch = ChrLatch::new(!self.mmc4);
+        self.ppu.watch_patterns = true;
+        self.ppu.set_chr_rom_page(0, &mut self.rom);
+        self.ppu.start(&mut self.rom);
+        return Ok(());
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0xf000 {
+            0xa000 => {
+                if self.mmc4 {
+                    self.rom.set_prgrom_page(0, (data & 0x0f) as usize);
+                } else {
+                    self.rom.set_prgrom_page_8k(0, (data & 0x0f) as isize);
+                }
+            }
+            0xb000 => self.set_chr_bank(0, LATCH_FD, data),
+            0xc000 => self.set_chr_bank(0, LATCH_FE, data),
+            0xd000 => self.set_chr_bank(1, LATCH_FD, data),
+            0xe000 => self.set_chr_bank(1, LATCH_FE, data),
+            0xf000 => {
+                self.ppu.set_mirroring(if (data & 0x01) != 0 {
+                    rom::Mirroring::HORIZONTAL
+                } else {
+                    rom::Mirroring::VERTICAL
+                });
+            }
+            _ => {}
+        }
+    }
+    fn pattern_fetch(&mut self, addr: usize) {
+        if let Some(half) = self.latch.fetch(addr) {
+            self.apply_latch(half);
+        }
+    }
+}
+impl Mapper9 {
+    pub fn new(rom: rom::Rom, ppu: ppu::Ppu, io: io::Io) -> Self {
+        Self {
+            rom,
+            ppu,
+            io,
+            mmc4: false,
+            latch: ChrLatch::new(true),
+        }
+    }
+    fn set_chr_bank(&mut self, half: usize, sel: usize, data: u8) {
+        self.latch.banks[half][sel] = (data & 0x1f) as usize;
+        self.apply_latch(half);
+    }
+    fn apply_latch(&mut self, half: usize) {
+        let bank = self.latch.bank(half);
+        for i in 0..4 {
+            self.ppu.set_chr_rom_data1k(
+                (half * 4 + i) as isize,
+                (bank * 4 + i) as isize,
+                &mut self.rom,
+            );
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::dma::Dma;
+use crate::mapper::MapperBase;
 use crate::ppu::Port;
-use crate::{mapper::MapperBase, mapper0};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
@@ -9,15 +9,21 @@ const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
 pub struct Mem {
     pub ram: Vec<u8>,
-    pub mapper: mapper0::Mapper0,
+    pub mapper: Box<dyn MapperBase>,
     pub dma: Dma,
+    // CPU cycles of the current step up to the end of the running
+    // instruction, and how many of them the PPU has already been given.
+    pub clock: usize,
+    pub ppu_synced: usize,
 }
 impl Mem {
-    pub fn new(mapper: mapper0::Mapper0) -> Self {
+    pub fn new(mapper: Box<dyn MapperBase>) -> Self {
         Self {
             ram: (0..0x800).map(|x| 0).collect(),
             mapper: mapper,
             dma: Dma::new(),
+            clock: 0,
+            ppu_synced: 0,
         }
     }
     pub fn init(&mut self) {
@@ -26,6 +32,15 @@ impl Mem {
         self.mapper.init();
     }
 
+    // Loads and stores touch their address on the last cycle of the
+    // instruction, so the PPU runs up to the start of that cycle first.
+    fn sync_ppu(&mut self) {
+        let target = self.clock.saturating_sub(1);
+        if target > self.ppu_synced {
+            self.mapper.run_ppu(target - self.ppu_synced);
+            self.ppu_synced = target;
+        }
+    }
     pub fn get16(&mut self, addr: u16) -> u16 {
         let l = self.get(addr);
         let h = self.get(addr + 1);
@@ -39,18 +54,16 @@ impl Mem {
                 return self.ram[(addr & 0x7ff) as usize] as u8;
             }
             0x2000 => {
+                self.sync_ppu();
                 match (addr & 0x0007) {
-                    0x0000 => {}
-                    0x0001 => {}
                     0x0002 => {
-                        return self.mapper.ppu.read_ppu_status_reg();
+                        return self.mapper.ppu().read_ppu_status_reg();
+                    }
+                    0x0004 => {
+                        return self.mapper.ppu().read_sprite_data();
                     }
-                    0x0003 => {}
-                    0x0004 => {}
-                    0x0005 => {}
-                    0x0006 => {}
                     0x0007 => {
-                        return self.mapper.ppu.read_ppu_data_reg();
+                        return self.mapper.ppu().read_ppu_data_reg();
                     }
                     0x0008..=PPU_REGISTERS_MIRRORS_END => {
                         let mirror_down_addr = addr & 0b00100000_00000111;
@@ -58,7 +71,7 @@ impl Mem {
                     }
                     _ => {}
                 }
-                return 0;
+                return self.mapper.ppu().read_open_bus();
             }
             0x3000 => match (addr) {
                 _ => {
@@ -90,13 +103,13 @@ impl Mem {
                 0x4014 => {}
                 0x4015 => {}
                 0x4016 => {
-                    let ret = self.mapper.io.get_latched_ctrl_state(1) & 1;
-                    self.mapper.io.set_latched_ctrl_state(1);
+                    let ret = self.mapper.io().get_latched_ctrl_state(1) & 1;
+                    self.mapper.io().set_latched_ctrl_state(1);
                     return ret | 0x40;
                 }
                 0x4017 => {
-                    let ret = self.mapper.io.get_latched_ctrl_state(2) & 1;
-                    self.mapper.io.set_latched_ctrl_state(2);
+                    let ret = self.mapper.io().get_latched_ctrl_state(2) & 1;
+                    self.mapper.io().set_latched_ctrl_state(2);
                     return ret | 0x40;
                 }
                 0x4018 => {}
@@ -111,21 +124,23 @@ impl Mem {
                     return self.mapper.read_low(addr);
                 }
             },
-            0x6000 => {}
+            0x6000 => {
+                return self.mapper.read_sram(addr);
+            }
             // 0x8000..=0xFFFF => {
             //     return self.mapper.rom.read_prg_rom(addr);
             // }
             0x8000 => {
-                return self.mapper.rom.roms[0][(addr & 0x1fff) as usize];
+                return self.mapper.rom().roms[0][(addr & 0x1fff) as usize];
             }
             0xa000 => {
-                return self.mapper.rom.roms[1][(addr & 0x1fff) as usize];
+                return self.mapper.rom().roms[1][(addr & 0x1fff) as usize];
             }
             0xc000 => {
-                return self.mapper.rom.roms[2][(addr & 0x1fff) as usize];
+                return self.mapper.rom().roms[2][(addr & 0x1fff) as usize];
             }
             0xe000 => {
-                return self.mapper.rom.roms[3][(addr & 0x1fff) as usize];
+                return self.mapper.rom().roms[3][(addr & 0x1fff) as usize];
             }
             _ => {}
         }
@@ -136,35 +151,39 @@ impl Mem {
             0x0000 => {
                 self.ram[(addr & 0x7ff) as usize] = data;
             }
-            0x2000 => match (addr & 0x07) {
-                0x00 => {
-                    self.mapper.ppu.write_ppu_ctrl0_reg(data);
-                }
-                0x01 => {
-                    self.mapper.ppu.write_ppu_ctrl1_reg(data);
-                }
-                0x02 => {}
-                0x03 => {
-                    self.mapper.ppu.write_sprite_addr_reg(data);
-                }
-                0x04 => {
-                    self.mapper.ppu.write_sprite_data(data);
-                }
-                0x05 => {
-                    self.mapper.ppu.write_scroll_reg(data);
-                }
-                0x06 => {
-                    self.mapper.ppu.write_ppu_addr_reg(data);
-                }
-                0x07 => {
-                    self.mapper.ppu.write_ppu_data_reg(data);
-                }
-                0x0008..=PPU_REGISTERS_MIRRORS_END => {
-                    let mirror_down_addr = addr & 0b00100000_00000111;
-                    self.set(mirror_down_addr, data);
+            0x2000 => {
+                self.sync_ppu();
+                self.mapper.ppu().write_open_bus(data);
+                match (addr & 0x07) {
+                    0x00 => {
+                        self.mapper.ppu().write_ppu_ctrl0_reg(data);
+                    }
+                    0x01 => {
+                        self.mapper.ppu().write_ppu_ctrl1_reg(data);
+                    }
+                    0x02 => {}
+                    0x03 => {
+                        self.mapper.ppu().write_sprite_addr_reg(data);
+                    }
+                    0x04 => {
+                        self.mapper.ppu().write_sprite_data(data);
+                    }
+                    0x05 => {
+                        self.mapper.ppu().write_scroll_reg(data);
+                    }
+                    0x06 => {
+                        self.mapper.ppu().write_ppu_addr_reg(data);
+                    }
+                    0x07 => {
+                        self.mapper.ppu().write_ppu_data_reg(data);
+                    }
+                    0x0008..=PPU_REGISTERS_MIRRORS_END => {
+                        let mirror_down_addr = addr & 0b00100000_00000111;
+                        self.set(mirror_down_addr, data);
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
             0x3000..=0x3fff => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.set(mirror_down_addr, data);
@@ -192,14 +211,14 @@ impl Mem {
                 0x4012 => {}
                 0x4013 => {}
                 0x4014 => {
-                    self.dma.run(data, &self.ram, &mut self.mapper.ppu);
+                    self.dma.run(data, &self.ram, self.mapper.ppu());
                 }
                 0x4015 => {}
                 0x4016 => {
                     if ((data & 0x01) > 0) {
-                        self.mapper.io.set_ctrllatched(true)
+                        self.mapper.io().set_ctrllatched(true)
                     } else {
-                        self.mapper.io.set_ctrllatched(false)
+                        self.mapper.io().set_ctrllatched(false)
                     }
                     return;
                 }
@@ -216,7 +235,9 @@ impl Mem {
                     self.mapper.write_low(addr, data);
                 }
             },
-            0x6000 => {}
+            0x6000 => {
+                self.mapper.write_sram(addr, data);
+            }
             0x8000 => {
                 self.mapper.write(addr, data);
             }
@@ -1,24 +1,62 @@
+use crate::audio;
+use crate::config;
 use crate::cpu;
+use crate::crt;
+use crate::filter;
+use crate::gifclip;
+use crate::hdpack;
+use crate::image;
 use crate::io;
 use crate::irq;
-use crate::mapper::MapperBase;
+use crate::levelmap;
+use crate::mapper;
 use crate::mapper0;
 use crate::mem;
 use crate::ppu;
+use crate::recorder;
 use crate::rom;
+#[cfg(unix)]
+use crate::terminal;
+use crate::viewer;
 
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
+#[cfg(feature = "sdl")]
+use sdl2::event::WindowEvent;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::Color;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::PixelFormatEnum;
+#[cfg(feature = "sdl")]
 use sdl2::rect::Point;
+#[cfg(feature = "sdl")]
+use sdl2::rect::Rect;
+#[cfg(feature = "sdl")]
 use sdl2::render::Canvas;
+#[cfg(feature = "sdl")]
 use sdl2::render::Texture;
+#[cfg(feature = "sdl")]
+use sdl2::render::TextureCreator;
+#[cfg(feature = "sdl")]
 use sdl2::video::Window;
+#[cfg(feature = "sdl")]
+use sdl2::video::WindowContext;
+#[cfg(feature = "sdl")]
+use sdl2::AudioSubsystem;
+#[cfg(feature = "sdl")]
 use sdl2::EventPump;
+#[cfg(feature = "sdl")]
+use std::cell::OnceCell;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 const WIDTH: u32 = 256;
-const HEIGHT: u32 = 224;
+const HEIGHT: u32 = 240;
 
 const PAD_A: u8 = 0x01;
 const PAD_B: u8 = 0x02;
@@ -28,10 +66,46 @@ const PAD_U: u8 = 0x10;
 const PAD_D: u8 = 0x20;
 const PAD_L: u8 = 0x40;
 const PAD_R: u8 = 0x80;
+// Frames a terminal key press holds its button, which bridges the gap
+// between key repeats.
+const HOLD_FRAMES: usize = 15;
+
+// The keys the frontends hand to the core, so the pad mapping does not
+// depend on SDL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    X,
+    Z,
+    A,
+    S,
+    UP,
+    DOWN,
+    LEFT,
+    RIGHT,
+    ESCAPE,
+}
+impl Key {
+    #[cfg(feature = "sdl")]
+    pub fn from_keycode(keycode: Keycode) -> Option<Self> {
+        match keycode {
+            Keycode::X => Some(Key::X),
+            Keycode::Z => Some(Key::Z),
+            Keycode::A => Some(Key::A),
+            Keycode::S => Some(Key::S),
+            Keycode::Up => Some(Key::UP),
+            Keycode::Down => Some(Key::DOWN),
+            Keycode::Left => Some(Key::LEFT),
+            Keycode::Right => Some(Key::RIGHT),
+            Keycode::Escape => Some(Key::ESCAPE),
+            _ => None,
+        }
+    }
+}
 
 pub struct Nes {
     cpu: cpu::Cpu,
     irq: irq::Irq,
+    hd_pack: Option<hdpack::HdPack>,
 }
 impl Nes {
     pub fn new() -> Self {
@@ -40,23 +114,55 @@ impl Nes {
         let rom = rom::Rom::new();
         let ppu = ppu::Ppu::new();
         let mapper = mapper0::Mapper0::new(rom, ppu, io);
-        let mem = mem::Mem::new(mapper);
+        let mem = mem::Mem::new(Box::new(mapper));
 
         Self {
             cpu: cpu::Cpu::new(mem),
             irq,
+            hd_pack: None,
         }
     }
     pub fn init(&mut self) {
         self.cpu.init();
         self.irq.init();
     }
-    pub fn set_rom(&mut self, mut buf: Vec<u8>) {
+    pub fn set_rom(&mut self, mut buf: Vec<u8>, filename: &str) -> Result<(), String> {
         println!("load rom");
+        let mut rom = rom::Rom::new();
+        rom.filename = filename.to_string();
+        self.cpu.mem.mapper = mapper::new_mapper(&buf, rom, ppu::Ppu::new(), io::Io::new())?;
         self.init();
-        self.cpu.mem.mapper.set_rom(buf);
+        return self.cpu.mem.mapper.set_rom(buf);
+    }
+    pub fn set_palette(&mut self, table: Vec<(u8, u8, u8)>) {
+        self.cpu.mem.mapper.ppu().set_palette_table(table);
+    }
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.cpu.mem.mapper.ppu().no_sprite_limit = !enabled;
+    }
+    pub fn set_hd_pack(&mut self, dir: &str) {
+        match hdpack::HdPack::load(dir, self.cpu.mem.mapper.rom()) {
+            Result::Ok(pack) => {
+                self.cpu.mem.mapper.ppu().record_sources = true;
+                self.hd_pack = Some(pack);
+            }
+            Result::Err(err) => eprintln!("Cannot load HD pack {}: {}", dir, err),
+        }
     }
-    pub fn start(&mut self, cputest: bool, mut event_pump: EventPump, mut canvas: Canvas<Window>) {
+    pub fn set_region(&mut self, region: rom::Region) {
+        println!("region {:?}", region);
+        self.cpu.mem.mapper.rom().region = region;
+        self.cpu.mem.mapper.ppu().set_region(region);
+    }
+    #[cfg(feature = "sdl")]
+    pub fn start(
+        &mut self,
+        cputest: bool,
+        mut event_pump: EventPump,
+        mut canvas: Canvas<Window>,
+        audio: Option<AudioSubsystem>,
+        config: &config::Config,
+    ) {
         let mut count = 0;
         let mut cputest = false;
         if cputest {
@@ -66,49 +172,216 @@ impl Nes {
         } else {
             self.cpu.start();
         }
-        self.main_loop(count, cputest, event_pump, canvas);
+        self.main_loop(count, cputest, event_pump, canvas, audio, config);
+    }
+
+    // Runs on the terminal instead of an SDL window, without sound. Keys
+    // only arrive as presses, so a press holds its button for a few frames
+    // and key repeat keeps it held.
+    #[cfg(unix)]
+    pub fn start_terminal(&mut self, mode: terminal::Mode, config: &config::Config) {
+        let mut term = match terminal::Terminal::new(mode, config) {
+            Result::Ok(term) => term,
+            Result::Err(err) => {
+                eprintln!("Cannot use the terminal: {}", err);
+                return;
+            }
+        };
+        self.cpu.start();
+        let region = self.cpu.mem.mapper.rom().region;
+        let mut audio = audio::Audio::new(region);
+        let frame_time = Duration::from_secs_f64(1.0 / region.frame_rate());
+        let mut next_frame = Instant::now() + frame_time;
+        let mut held = [0; 8];
+
+        loop {
+            if !self.step(false, &mut audio) {
+                continue;
+            }
+            term.draw(&self.cpu.mem.mapper.ppu().imgdata);
+            self.cpu.mem.mapper.ppu().clear_img();
+
+            for key in term.poll_keys() {
+                if key == Key::ESCAPE {
+                    self.cpu.mem.mapper.save();
+                    return;
+                }
+                let pad = self.key_to_pad(key);
+                for bit in 0..8 {
+                    if (pad >> bit) & 0x01 != 0 {
+                        held[bit] = HOLD_FRAMES;
+                    }
+                }
+            }
+            let mut pad = 0;
+            for bit in 0..8 {
+                if held[bit] > 0 {
+                    held[bit] -= 1;
+                    pad |= 1 << bit;
+                }
+            }
+            self.cpu.mem.mapper.io().set_ctrlstat1(pad);
+
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+                next_frame += frame_time;
+            } else {
+                next_frame = now + frame_time;
+            }
+        }
     }
+    // Runs one CPU instruction and lets everything else catch up. Returns
+    // true once a frame is finished.
+    pub fn step(&mut self, cputest: bool, audio: &mut audio::Audio) -> bool {
+        if (self.cpu.mem.mapper.io().get_ctrllatched()) {
+            self.cpu.mem.mapper.io().hdCtrlLatch();
+        }
 
+        self.cpu.run(&mut self.irq, cputest);
+        if self.cpu.mem.dma.get_status() {
+            self.cpu.mem.dma.clear();
+            self.cpu.cpuclock += 514;
+        }
+        let remaining = self.cpu.cpuclock as usize - self.cpu.mem.ppu_synced;
+        self.cpu.mem.ppu_synced = 0;
+        self.cpu.mem.clock = 0;
+        self.cpu.mem.mapper.run_ppu(remaining);
+        self.cpu.mem.mapper.ppu().raise_nmi(&mut self.irq);
+        self.cpu
+            .mem
+            .mapper
+            .cpusync(self.cpu.cpuclock as usize, &mut self.irq);
+        audio.run(self.cpu.cpuclock as usize, &mut self.cpu.mem.mapper);
+        self.cpu.clear_cpucycle();
+
+        return self.cpu.mem.mapper.ppu().get_img_status().0;
+    }
+    #[cfg(feature = "sdl")]
     pub fn main_loop(
         &mut self,
         mut count: usize,
         cputest: bool,
         mut event_pump: EventPump,
         mut canvas: Canvas<Window>,
+        audio: Option<AudioSubsystem>,
+        config: &config::Config,
     ) {
         let mut i = 0;
         let mut pad = 0;
         let player = 1;
+        let mut oam_cursor = 0;
         let creator = canvas.texture_creator();
+        let mut filter_index = filter::FILTERS
+            .iter()
+            .position(|&name| name == config.filter)
+            .unwrap_or(0);
+        let mut filter = filter::new_filter(&config.filter, config);
+        let mut scale = output_scale(&filter, &self.hd_pack);
+        let mut filtered = Vec::new();
+        let mut crt = if config.crt > 0.0 {
+            let (width, height) = canvas.output_size().unwrap();
+            Some(crt::Crt::new(config, width, height))
+        } else {
+            None
+        };
+        let mut crt_frame = Vec::new();
+        let (width, height) = texture_size(scale, &crt);
         let mut texture = creator
-            .create_texture_target(PixelFormatEnum::RGB24, WIDTH, HEIGHT)
+            .create_texture_target(PixelFormatEnum::RGB24, width, height)
             .unwrap();
+        let region = self.cpu.mem.mapper.rom().region;
+        let subsystem = audio;
+        let mut audio = audio::Audio::new(region);
+        if let Some(subsystem) = subsystem {
+            audio.open(subsystem);
+        }
+        let frame_time = Duration::from_secs_f64(1.0 / region.frame_rate());
+        let mut next_frame = Instant::now() + frame_time;
+        let mut crop = crop_rect(config, scale, &crt);
+        let main_window = canvas.window().id();
+        let mut viewer = viewer::Viewer::new();
+        // The VRAM window is built on the first F5 and only hidden and shown
+        // after that, so its texture can live for the whole loop.
+        let viewer_creator: OnceCell<TextureCreator<WindowContext>> = OnceCell::new();
+        let mut viewer_canvas: Option<Canvas<Window>> = None;
+        let mut viewer_texture: Option<Texture> = None;
+        let mut viewer_open = false;
+        let mut screenshot_pending = false;
+        let mut recorder = None;
+        let mut gif_clip = gifclip::GifClip::new(config.gif_seconds, region.frame_rate());
+        if !config.record.is_empty() {
+            recorder = start_recording(&config.record, region, &mut audio);
+        }
+        let mut level_map = if config.map.is_empty() {
+            None
+        } else {
+            Some(levelmap::LevelMap::new())
+        };
 
         while count == 0 || count != i {
             i += 1;
 
-            if (self.cpu.mem.mapper.io.get_ctrllatched()) {
-                self.cpu.mem.mapper.io.hdCtrlLatch();
-            }
-
-            self.cpu.run(&mut self.irq, cputest);
-            if self.cpu.mem.dma.get_status() {
-                self.cpu.mem.dma.clear();
-                self.cpu.cpuclock += 514;
-            }
-            self.cpu
-                .mem
-                .mapper
-                .ppu
-                .run(self.cpu.cpuclock as usize, &mut self.irq);
-            self.cpu.clear_cpucycle();
-
-            let imgopt = self.cpu.mem.mapper.ppu.get_img_status();
-            if imgopt.0 {
-                texture.update(None, imgopt.1, 256 * 3).unwrap();
-                canvas.copy(&texture, None, None).unwrap();
+            if self.step(cputest, &mut audio) {
+                let ppu = self.cpu.mem.mapper.ppu();
+                let frame = match (self.hd_pack.as_mut(), filter.as_mut()) {
+                    (Some(hd_pack), _) => {
+                        hd_pack.render(ppu, &self.cpu.mem.ram, &mut filtered);
+                        &filtered
+                    }
+                    (None, Some(filter)) => {
+                        filter.apply(&ppu.imgdata, &ppu.idxdata, &mut filtered);
+                        &filtered
+                    }
+                    (None, None) => &ppu.imgdata,
+                };
+                match crt.as_mut() {
+                    Some(crt) => {
+                        crt.apply(frame, scale, &mut crt_frame);
+                        let pitch = crt.size().0 as usize * 3;
+                        texture.update(None, &crt_frame, pitch).unwrap();
+                    }
+                    None => texture
+                        .update(None, frame, (WIDTH * scale.0 * 3) as usize)
+                        .unwrap(),
+                }
+                canvas.copy(&texture, crop, None).unwrap();
                 canvas.present();
-                self.cpu.mem.mapper.ppu.clear_img();
+                gif_clip.push(&self.cpu.mem.mapper.ppu().idxdata);
+                if let Some(rec) = recorder.as_mut() {
+                    let ppu = self.cpu.mem.mapper.ppu();
+                    let sound = audio.captured.replace(Vec::new()).unwrap_or_default();
+                    if let Err(err) = rec.add_frame(&ppu.imgdata, &sound) {
+                        eprintln!("Recording stopped: {}", err);
+                        stop_recording(recorder.take(), &mut audio);
+                    }
+                }
+                if screenshot_pending {
+                    screenshot_pending = false;
+                    self.take_screenshot(config.screenshot_indexed);
+                }
+                if let Some(level_map) = level_map.as_mut() {
+                    level_map.capture(self.cpu.mem.mapper.ppu());
+                }
+                if let (true, Some(viewer_canvas), Some(viewer_texture)) =
+                    (viewer_open, viewer_canvas.as_mut(), viewer_texture.as_mut())
+                {
+                    viewer.render(self.cpu.mem.mapper.ppu());
+                    viewer_texture
+                        .update(None, &viewer.pixels, viewer::WIDTH * 3)
+                        .unwrap();
+                    viewer_canvas.copy(viewer_texture, None, None).unwrap();
+                    viewer_canvas.present();
+                }
+                self.cpu.mem.mapper.ppu().clear_img();
+
+                let now = Instant::now();
+                if next_frame > now {
+                    thread::sleep(next_frame - now);
+                    next_frame += frame_time;
+                } else {
+                    next_frame = now + frame_time;
+                }
             }
             for event in event_pump.poll_iter() {
                 match event {
@@ -116,15 +389,191 @@ impl Nes {
                     | Event::KeyDown {
                         keycode: Some(Keycode::Escape),
                         ..
-                    } => std::process::exit(0),
+                    } => {
+                        self.save_map(&level_map, &config.map);
+                        stop_recording(recorder.take(), &mut audio);
+                        self.cpu.mem.mapper.save();
+                        std::process::exit(0)
+                    }
+                    Event::Window {
+                        window_id,
+                        win_event: WindowEvent::Close,
+                        ..
+                    } => {
+                        if window_id == main_window {
+                            self.save_map(&level_map, &config.map);
+                            stop_recording(recorder.take(), &mut audio);
+                            self.cpu.mem.mapper.save();
+                            std::process::exit(0)
+                        }
+                        if let Some(viewer_canvas) = viewer_canvas.as_mut() {
+                            viewer_canvas.window_mut().hide();
+                        }
+                        viewer_open = false;
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F),
+                        ..
+                    } => {
+                        filter_index = (filter_index + 1) % filter::FILTERS.len();
+                        println!("filter {}", filter::FILTERS[filter_index]);
+                        filter = filter::new_filter(filter::FILTERS[filter_index], config);
+                        scale = output_scale(&filter, &self.hd_pack);
+                        let (width, height) = texture_size(scale, &crt);
+                        texture = creator
+                            .create_texture_target(PixelFormatEnum::RGB24, width, height)
+                            .unwrap();
+                        crop = crop_rect(config, scale, &crt);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F1),
+                        ..
+                    } => {
+                        let ppu = self.cpu.mem.mapper.ppu();
+                        let visible = !ppu.is_bg_visible();
+                        ppu.set_bg_visible(visible);
+                        println!("background {}", if visible { "on" } else { "off" });
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F2),
+                        ..
+                    } => {
+                        let ppu = self.cpu.mem.mapper.ppu();
+                        let visible = !ppu.is_sprites_visible();
+                        ppu.set_sprites_visible(visible);
+                        println!("sprites {}", if visible { "on" } else { "off" });
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageUp),
+                        ..
+                    } => {
+                        oam_cursor = (oam_cursor + 1) & 0x3f;
+                        println!("OAM entry {}", oam_cursor);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageDown),
+                        ..
+                    } => {
+                        oam_cursor = (oam_cursor + 63) & 0x3f;
+                        println!("OAM entry {}", oam_cursor);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F3),
+                        ..
+                    } => {
+                        let ppu = self.cpu.mem.mapper.ppu();
+                        let hidden = !ppu.is_sprite_hidden(oam_cursor);
+                        ppu.set_sprite_hidden(oam_cursor, hidden);
+                        println!(
+                            "OAM entry {} {}",
+                            oam_cursor,
+                            if hidden { "hidden" } else { "shown" }
+                        );
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F4),
+                        ..
+                    } => {
+                        self.cpu.mem.mapper.ppu().show_all_sprites();
+                        println!("all OAM entries shown");
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        ..
+                    } => {
+                        viewer_open = !viewer_open;
+                        match viewer_canvas.as_mut() {
+                            Some(viewer_canvas) if viewer_open => viewer_canvas.window_mut().show(),
+                            Some(viewer_canvas) => viewer_canvas.window_mut().hide(),
+                            None => {
+                                let window = canvas
+                                    .window()
+                                    .subsystem()
+                                    .window("VRAM", viewer::WIDTH as u32, viewer::HEIGHT as u32)
+                                    .build()
+                                    .unwrap();
+                                let new_canvas = window.into_canvas().build().unwrap();
+                                viewer_texture = Some(
+                                    viewer_creator
+                                        .get_or_init(|| new_canvas.texture_creator())
+                                        .create_texture_streaming(
+                                            PixelFormatEnum::RGB24,
+                                            viewer::WIDTH as u32,
+                                            viewer::HEIGHT as u32,
+                                        )
+                                        .unwrap(),
+                                );
+                                viewer_canvas = Some(new_canvas);
+                            }
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F6),
+                        ..
+                    } => {
+                        viewer.next_palette();
+                        println!("pattern table palette {}", viewer.palette);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F7),
+                        ..
+                    } => {
+                        let path = self.output_path("-vram.png");
+                        match viewer.dump_png(self.cpu.mem.mapper.ppu(), &path) {
+                            Result::Ok(_) => println!("VRAM saved to {}", path),
+                            Result::Err(err) => eprintln!("Cannot write {}: {}", path, err),
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F9),
+                        ..
+                    } => {
+                        // The frame buffer is only whole between frames.
+                        screenshot_pending = true;
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F10),
+                        ..
+                    } => {
+                        if recorder.is_some() {
+                            stop_recording(recorder.take(), &mut audio);
+                        } else {
+                            let path = self.output_path(&format!("-{}.avi", timestamp()));
+                            recorder = start_recording(&path, region, &mut audio);
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F11),
+                        ..
+                    } => {
+                        let path = self.output_path(&format!("-{}.gif", timestamp()));
+                        let ppu = self.cpu.mem.mapper.ppu();
+                        let palette: Vec<(u8, u8, u8)> = (0..64).map(|c| ppu.rgb(c)).collect();
+                        match gif_clip.save(&path, &palette) {
+                            Result::Ok(_) => println!("clip saved to {}", path),
+                            Result::Err(err) => eprintln!("Cannot write {}: {}", path, err),
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F8),
+                        ..
+                    } => {
+                        self.save_map(&level_map, &config.map);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Tab),
+                        ..
+                    } => {
+                        self.cpu.mem.mapper.switch_disk();
+                    }
                     Event::KeyDown {
                         keycode: Some(key), ..
                     } => {
                         pad |= self.keycode_to_pad(key);
                         if (player == 1) {
-                            self.cpu.mem.mapper.io.set_ctrlstat1(pad);
+                            self.cpu.mem.mapper.io().set_ctrlstat1(pad);
                         } else if (player == 2) {
-                            self.cpu.mem.mapper.io.set_ctrlstat2(pad);
+                            self.cpu.mem.mapper.io().set_ctrlstat2(pad);
                         }
                     }
                     Event::KeyUp {
@@ -132,9 +581,9 @@ impl Nes {
                     } => {
                         pad &= !self.keycode_to_pad(key);
                         if (player == 1) {
-                            self.cpu.mem.mapper.io.set_ctrlstat1(pad);
+                            self.cpu.mem.mapper.io().set_ctrlstat1(pad);
                         } else if (player == 2) {
-                            self.cpu.mem.mapper.io.set_ctrlstat2(pad);
+                            self.cpu.mem.mapper.io().set_ctrlstat2(pad);
                         }
                     }
                     _ => {}
@@ -142,17 +591,176 @@ impl Nes {
             }
         }
     }
-    pub fn keycode_to_pad(&mut self, key: Keycode) -> u8 {
+    // Saves the last finished frame as RGB. Call it between frames.
+    pub fn save_screenshot(&mut self, path: &str) -> Result<(), String> {
+        let ppu = self.cpu.mem.mapper.ppu();
+        return image::write_png(path, WIDTH, HEIGHT, &ppu.imgdata);
+    }
+    // Saves the last finished frame as palette indices 0-63, with the 64
+    // base colours as the PNG palette. Emphasis does not fit in an index, so
+    // its bits go in an "Emphasis" text chunk.
+    pub fn save_indexed_screenshot(&mut self, path: &str) -> Result<(), String> {
+        let ppu = self.cpu.mem.mapper.ppu();
+        let indices: Vec<u8> = ppu.idxdata.iter().map(|&i| (i & 0x3f) as u8).collect();
+        let palette: Vec<(u8, u8, u8)> = (0..64).map(|color| ppu.rgb(color)).collect();
+        let emphasis = ppu.idxdata[0] >> 6;
+        let text = if ppu.idxdata.iter().all(|&i| i >> 6 == emphasis) {
+            format!("{}", emphasis)
+        } else {
+            "mixed".to_string()
+        };
+        return image::write_indexed_png(
+            path,
+            WIDTH,
+            HEIGHT,
+            &indices,
+            &palette,
+            &[("Emphasis", text)],
+        );
+    }
+    fn take_screenshot(&mut self, indexed: bool) {
+        let stamp = timestamp();
+        let path = self.output_path(&format!("-{}.png", stamp));
+        match self.save_screenshot(&path) {
+            Result::Ok(_) => println!("screenshot saved to {}", path),
+            Result::Err(err) => eprintln!("Cannot write {}: {}", path, err),
+        }
+        if indexed {
+            let path = self.output_path(&format!("-{}-idx.png", stamp));
+            match self.save_indexed_screenshot(&path) {
+                Result::Ok(_) => println!("screenshot saved to {}", path),
+                Result::Err(err) => eprintln!("Cannot write {}: {}", path, err),
+            }
+        }
+    }
+    // The ROM's path without its extension, followed by `suffix`.
+    fn output_path(&mut self, suffix: &str) -> String {
+        let rom = &self.cpu.mem.mapper.rom().filename;
+        let stem = Path::new(rom).with_extension("");
+        return format!("{}{}", stem.display(), suffix);
+    }
+    pub fn save_map(&mut self, level_map: &Option<levelmap::LevelMap>, path: &str) {
+        if let Some(level_map) = level_map {
+            match level_map.save_png(self.cpu.mem.mapper.ppu(), path) {
+                Result::Ok(_) => println!("level map saved to {}", path),
+                Result::Err(err) => eprintln!("Cannot write {}: {}", path, err),
+            }
+        }
+    }
+    pub fn key_to_pad(&mut self, key: Key) -> u8 {
         match key {
-            Keycode::X => PAD_A,
-            Keycode::Z => PAD_B,
-            Keycode::A => PAD_SELECT,
-            Keycode::S => PAD_START,
-            Keycode::Up => PAD_U,
-            Keycode::Down => PAD_D,
-            Keycode::Left => PAD_L,
-            Keycode::Right => PAD_R,
-            _ => 0,
+            Key::X => PAD_A,
+            Key::Z => PAD_B,
+            Key::A => PAD_SELECT,
+            Key::S => PAD_START,
+            Key::UP => PAD_U,
+            Key::DOWN => PAD_D,
+            Key::LEFT => PAD_L,
+            Key::RIGHT => PAD_R,
+            Key::ESCAPE => 0,
+        }
+    }
+    #[cfg(feature = "sdl")]
+    fn keycode_to_pad(&mut self, keycode: Keycode) -> u8 {
+        match Key::from_keycode(keycode) {
+            Some(key) => self.key_to_pad(key),
+            None => 0,
         }
     }
 }
+
+// Recording starts with the next frame; the sound captured from here on
+// belongs to it.
+fn start_recording(
+    path: &str,
+    region: rom::Region,
+    audio: &mut audio::Audio,
+) -> Option<recorder::Recorder> {
+    match recorder::Recorder::new(path, region.frame_rate()) {
+        Result::Ok(recorder) => {
+            println!("recording to {}", path);
+            audio.captured = Some(Vec::new());
+            return Some(recorder);
+        }
+        Result::Err(err) => {
+            eprintln!("Cannot record to {}: {}", path, err);
+            return None;
+        }
+    }
+}
+
+fn stop_recording(recorder: Option<recorder::Recorder>, audio: &mut audio::Audio) {
+    audio.captured = None;
+    if let Some(recorder) = recorder {
+        match recorder.finish() {
+            Result::Ok(_) => println!("recording stopped"),
+            Result::Err(err) => eprintln!("Cannot finish recording: {}", err),
+        }
+    }
+}
+
+// UTC date and time down to milliseconds, e.g. 20240131-235959-123.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (h, m, s) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+    // Civil date from days since the epoch, with years starting in March.
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        h,
+        m,
+        s,
+        now.subsec_millis()
+    );
+}
+
+// An HD pack replaces the output filter.
+fn output_scale(
+    filter: &Option<Box<dyn filter::Filter>>,
+    hd_pack: &Option<hdpack::HdPack>,
+) -> (u32, u32) {
+    if let Some(hd_pack) = hd_pack {
+        return (hd_pack.scale as u32, hd_pack.scale as u32);
+    }
+    match filter {
+        Some(filter) => filter.scale(),
+        None => (1, 1),
+    }
+}
+
+// The CRT stage draws the window's size from the frame at any scale.
+fn texture_size(scale: (u32, u32), crt: &Option<crt::Crt>) -> (u32, u32) {
+    match crt {
+        Some(crt) => crt.size(),
+        None => (WIDTH * scale.0, HEIGHT * scale.1),
+    }
+}
+
+// The overscan settings are in NES pixels; the texture may be scaled up.
+// The CRT stage has already left the overscan out.
+#[cfg(feature = "sdl")]
+fn crop_rect(config: &config::Config, scale: (u32, u32), crt: &Option<crt::Crt>) -> Option<Rect> {
+    if crt.is_some() {
+        return None;
+    }
+    return Some(Rect::new(
+        (config.overscan.left * scale.0) as i32,
+        (config.overscan.top * scale.1) as i32,
+        config.width() * scale.0,
+        config.height() * scale.1,
+    ));
+}
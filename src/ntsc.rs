@@ -0,0 +1,121 @@
+use crate::filter::Filter;
+use crate::palette;
+
+// The PPU outputs 8 samples per pixel of a waveform whose colour subcarrier
+// repeats every 12 samples, and a 341-dot line is 2728 samples, so each line
+// starts 4 phases later than the one above it. NTSC frames alternate their
+// length by one dot, which moves the starting phase back and forth between
+// frames and makes the artifacts crawl.
+//
+// The 12 in "12 samples" is the subcarrier period, not the pixel width. A
+// pixel lasts 4 master clocks (21.48 MHz) and a subcarrier cycle 6, and the
+// PPU's signal generator switches on both edges of the master clock, so it
+// can change level 8 times per pixel and 12 times per subcarrier cycle. A
+// cycle therefore spans a pixel and a half. Sampling each pixel 12 times
+// would squeeze the cycle into one pixel, and the fringes would no longer
+// fall where they do on a TV.
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_PIXEL;
+const OUT_PER_PIXEL: usize = 2;
+const OUT_WIDTH: usize = 256 * OUT_PER_PIXEL;
+const STEP: usize = SAMPLES_PER_PIXEL / OUT_PER_PIXEL;
+
+// Luma is averaged over less than a subcarrier cycle so chroma leaks into it
+// (dot crawl and fringing); chroma over two cycles, which smears colour
+// across neighbouring pixels.
+const LUMA_WIDTH: usize = 8;
+const CHROMA_WIDTH: usize = 24;
+const GAMMA_STEPS: usize = 1024;
+
+pub struct NtscFilter {
+    settings: palette::NtscSettings,
+    levels: Vec<[f32; 12]>,
+    cos: Vec<f32>,
+    sin: Vec<f32>,
+    gamma: Vec<u8>,
+    frame: usize,
+
+    sum_y: Vec<f32>,
+    sum_i: Vec<f32>,
+    sum_q: Vec<f32>,
+}
+impl NtscFilter {
+    pub fn new(settings: &palette::NtscSettings) -> Self {
+        let mut levels = vec![[0.0; 12]; 512];
+        for index in 0..512 {
+            for phase in 0..12 {
+                levels[index][phase] = palette::signal(index & 0x3f, index >> 6, phase);
+            }
+        }
+        Self {
+            settings: *settings,
+            levels,
+            cos: (0..12)
+                .map(|p| palette::carrier_angle(p, settings).cos())
+                .collect(),
+            sin: (0..12)
+                .map(|p| palette::carrier_angle(p, settings).sin())
+                .collect(),
+            gamma: (0..GAMMA_STEPS)
+                .map(|v| palette::to_byte(v as f32 / (GAMMA_STEPS - 1) as f32, settings.gamma))
+                .collect(),
+            frame: 0,
+
+            sum_y: vec![0.0; LINE_SAMPLES + 1],
+            sum_i: vec![0.0; LINE_SAMPLES + 1],
+            sum_q: vec![0.0; LINE_SAMPLES + 1],
+        }
+    }
+    // Running sums of the line's signal and its products with the carrier,
+    // so each output pixel's filter windows cost two lookups.
+    fn encode_line(&mut self, indices: &[u16], phase: usize) {
+        let mut n = 0;
+        for x in 0..256 {
+            let levels = &self.levels[indices[x] as usize & 0x1ff];
+            for k in 0..SAMPLES_PER_PIXEL {
+                let p = (phase + x * SAMPLES_PER_PIXEL + k) % 12;
+                let value = levels[p];
+                self.sum_y[n + 1] = self.sum_y[n] + value;
+                self.sum_i[n + 1] = self.sum_i[n] + value * self.cos[p];
+                self.sum_q[n + 1] = self.sum_q[n] + value * self.sin[p];
+                n += 1;
+            }
+        }
+    }
+    fn window(sums: &Vec<f32>, center: usize, width: usize) -> f32 {
+        let start = center.saturating_sub(width / 2);
+        let end = (center + width / 2).min(LINE_SAMPLES);
+        return (sums[end] - sums[start]) / (end - start) as f32;
+    }
+    fn to_byte(&self, value: f32) -> u8 {
+        let v = value.max(0.0).min(1.0);
+        return self.gamma[(v * (GAMMA_STEPS - 1) as f32) as usize];
+    }
+}
+impl Filter for NtscFilter {
+    fn scale(&self) -> (u32, u32) {
+        return (OUT_PER_PIXEL as u32, 1);
+    }
+    fn apply(&mut self, rgb: &[u8], indices: &[u16], out: &mut Vec<u8>) {
+        out.resize(OUT_WIDTH * 240 * 3, 0);
+        let frame_phase = (self.frame & 0x01) * 4;
+        self.frame += 1;
+
+        for line in 0..240 {
+            let phase = (frame_phase + line * 4) % 12;
+            self.encode_line(&indices[line * 256..(line + 1) * 256], phase);
+
+            let row = line * OUT_WIDTH * 3;
+            for o in 0..OUT_WIDTH {
+                let center = o * STEP + STEP / 2;
+                let y = NtscFilter::window(&self.sum_y, center, LUMA_WIDTH);
+                let i = NtscFilter::window(&self.sum_i, center, CHROMA_WIDTH);
+                let q = NtscFilter::window(&self.sum_q, center, CHROMA_WIDTH);
+                let (r, g, b) = palette::yiq_to_linear(y, i, q, &self.settings);
+                out[row + o * 3] = self.to_byte(r);
+                out[row + o * 3 + 1] = self.to_byte(g);
+                out[row + o * 3 + 2] = self.to_byte(b);
+            }
+        }
+    }
+}
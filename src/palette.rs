@@ -0,0 +1,132 @@
+use std::f32::consts::PI;
+use std::fs;
+
+// Signal levels of the 2C02's composite output, relative to sync, for the
+// low and high half of each waveform at luma levels 0-3.
+const LEVEL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const LEVEL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+#[derive(Clone, Copy)]
+pub struct NtscSettings {
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+impl NtscSettings {
+    pub fn new() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+// Reads a .pal file. 192-byte files hold the 64 base colours, 1536-byte
+// files all 512 emphasis combinations.
+pub fn load_pal(path: &str) -> Result<Vec<(u8, u8, u8)>, String> {
+    let buf = fs::read(path).map_err(|err| err.to_string())?;
+    if buf.len() != 64 * 3 && buf.len() != 512 * 3 {
+        return Err(format!("unexpected size {} bytes", buf.len()));
+    }
+    return Ok(buf.chunks(3).map(|c| (c[0], c[1], c[2])).collect());
+}
+
+// Builds all 512 colours by synthesising the composite waveform of each
+// colour over one colour subcarrier cycle (12 samples) and decoding it as
+// YIQ.
+pub fn generate(settings: &NtscSettings) -> Vec<(u8, u8, u8)> {
+    let mut table = Vec::with_capacity(512);
+    for emphasis in 0..8 {
+        for color in 0..64 {
+            table.push(generate_color(color, emphasis, settings));
+        }
+    }
+    return table;
+}
+
+pub fn in_color_phase(hue: usize, phase: usize) -> bool {
+    return (hue + phase) % 12 < 6;
+}
+
+// Composite signal for palette entry `color` with emphasis bits `emphasis`
+// at one of the 12 subcarrier phases, normalised so black is 0 and white 1.
+pub fn signal(color: usize, emphasis: usize, phase: usize) -> f32 {
+    let hue = color & 0x0f;
+    let level = if hue > 13 { 1 } else { (color >> 4) & 0x03 };
+    let mut low = LEVEL_LOW[level];
+    let mut high = LEVEL_HIGH[level];
+    if hue == 0 {
+        low = high;
+    } else if hue > 12 {
+        high = low;
+    }
+    let mut value = if in_color_phase(hue, phase) {
+        high
+    } else {
+        low
+    };
+
+    if hue < 14
+        && (((emphasis & 0x01) != 0 && in_color_phase(0x0c, phase))
+            || ((emphasis & 0x02) != 0 && in_color_phase(0x04, phase))
+            || ((emphasis & 0x04) != 0 && in_color_phase(0x08, phase)))
+    {
+        value *= EMPHASIS_ATTENUATION;
+    }
+    return (value - BLACK) / (WHITE - BLACK);
+}
+
+fn generate_color(color: usize, emphasis: usize, settings: &NtscSettings) -> (u8, u8, u8) {
+    let mut y = 0.0;
+    let mut i = 0.0;
+    let mut q = 0.0;
+    for phase in 0..12 {
+        let value = signal(color, emphasis, phase);
+        let angle = carrier_angle(phase, settings);
+        y += value;
+        i += value * angle.cos();
+        q += value * angle.sin();
+    }
+    return yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, settings);
+}
+
+// Decoder reference angle for a subcarrier phase. The 4 phase offset lines
+// the decoder up with the colour burst.
+pub fn carrier_angle(phase: usize, settings: &NtscSettings) -> f32 {
+    return PI * (phase as f32 + 4.0 + settings.hue / 30.0) / 6.0;
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, settings: &NtscSettings) -> (u8, u8, u8) {
+    let (r, g, b) = yiq_to_linear(y, i, q, settings);
+    return (
+        to_byte(r, settings.gamma),
+        to_byte(g, settings.gamma),
+        to_byte(b, settings.gamma),
+    );
+}
+
+// RGB before gamma correction, nominally in 0.0-1.0.
+pub fn yiq_to_linear(y: f32, i: f32, q: f32, settings: &NtscSettings) -> (f32, f32, f32) {
+    let y = y * settings.contrast + settings.brightness;
+    let i = i * settings.saturation;
+    let q = q * settings.saturation;
+    return (
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    );
+}
+
+// The decoded values are for a 2.2 gamma display; other gammas bend them.
+pub fn to_byte(value: f32, gamma: f32) -> u8 {
+    let v = value.max(0.0).min(1.0).powf(2.2 / gamma);
+    return (v * 255.0 + 0.5) as u8;
+}
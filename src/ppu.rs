@@ -1,35 +1,130 @@
 use crate::irq;
 use crate::rom;
 use rom::Mirroring;
+use std::collections::VecDeque;
 
+// Rendering steps one dot at a time: 341 dots per line, lines 0-239
+// visible and the last line of the frame is the pre-render line. NTSC has
+// 262 lines with vblank from 241; PAL and Dendy have 312, with Dendy moving
+// vblank down to 291 so that it stays as long as NTSC's.
 pub struct Ppu {
     ppux: usize,
     line: usize,
+    odd_frame: bool,
+    pub region: rom::Region,
+    scanlines: usize,
+    vblank_line: usize,
+    dots_per_5_cycles: usize,
+    dot_remainder: usize,
     regs: Vec<u8>,
     pub imgdata: Vec<u8>,
+    // The same frame as palette indices with the emphasis bits in 6-8, for
+    // filters that work from the video signal rather than RGB.
+    pub idxdata: Vec<u16>,
     imgok: bool,
-    imgidx: usize,
-    rcount: usize,
+    nmi_pending: bool,
+    vbl_suppressed: bool,
 
-    sprite_zero: bool,
-    scroll_reg_flg: bool,
+    // Loopy registers: v, t, fine x and the shared $2005/$2006 write toggle.
+    ppu_addr: usize,
     ppu_addr_buffer: usize,
     h_scroll_val: usize,
-    ppu_addr_reg_flg: bool,
-    ppu_addr: usize,
+    write_toggle: bool,
     ppu_read_buffer: usize,
 
+    // The data bus between the CPU and the PPU holds the last value driven
+    // onto it. Each bit fades to 0 if it is not refreshed for about 600ms.
+    io_latch: u8,
+    io_refresh: Vec<usize>,
+    frame: usize,
+    decay_frames: usize,
+
     screen_mirroring: Mirroring,
     vram: Vec<Vec<u8>>,
     vrams: Vec<Vec<u8>>,
+    nametables: Vec<usize>,
+
+    bg_next_name: u8,
+    bg_next_attr: u8,
+    bg_next_low: u8,
+    bg_next_high: u8,
+    bg_shift_low: u16,
+    bg_shift_high: u16,
+    at_shift_low: u16,
+    at_shift_high: u16,
 
-    bg_line_buffer: Vec<u8>,
-    sp_line_buffer: Vec<u16>,
+    secondary_oam: Vec<u8>,
+    eval_addr: usize,
+    eval_copy: usize,
+    eval_count: usize,
+    eval_data: u8,
+    eval_done: bool,
+    eval_zero: bool,
+
+    sp_count: usize,
+    sp_zero: bool,
+    sp_low: Vec<u8>,
+    sp_high: Vec<u8>,
+    sp_attr: Vec<u8>,
+    sp_x: Vec<u8>,
+    sp_index: Vec<u8>,
+    secondary_index: Vec<u8>,
 
     palette: Vec<u8>,
+    palette_table: Vec<(u8, u8, u8)>,
     pub sprite_ram: Vec<u8>,
-    spbit_pattern: Vec<Vec<Vec<u8>>>,
+    pub no_sprite_limit: bool,
+
+    // Debug switches that only change what reaches the frame buffers.
+    show_bg: bool,
+    show_sprites: bool,
+    hidden_sprites: u64,
+
+    // Per-pixel tile sources, only kept while `record_sources` is set.
+    pub record_sources: bool,
+    pub sources: Vec<PixelSource>,
+    bg_next_source: TilePixel,
+    bg_sources: [TilePixel; 2],
+    bg_shift_count: usize,
+    sp_sources: Vec<TilePixel>,
+
+    // Set by mappers that switch banks on what the PPU reads; the stepping
+    // then stops after every pattern fetch so the mapper can look at it.
+    // $2007 reads land here too, between steps.
+    pub watch_patterns: bool,
+    pattern_fetches: VecDeque<usize>,
+    dots_due: usize,
+}
+// The tile one layer of a screen pixel came from, for HD packs. `x` and `y`
+// are the pixel's position in the unflipped 8x8 tile and `value` its 2-bit
+// colour, 0 where the layer is transparent.
+#[derive(Clone, Copy)]
+pub struct TilePixel {
+    pub tile: [u8; 16],
+    pub palette: u8,
+    pub attr: u8,
+    pub x: u8,
+    pub y: u8,
+    pub value: u8,
+}
+impl TilePixel {
+    pub fn new() -> Self {
+        Self {
+            tile: [0; 16],
+            palette: 0,
+            attr: 0,
+            x: 0,
+            y: 0,
+            value: 0,
+        }
+    }
+}
+#[derive(Clone, Copy)]
+pub struct PixelSource {
+    pub bg: TilePixel,
+    pub sprite: TilePixel,
 }
+
 pub trait Port {
     fn write_scroll_reg(&mut self, value: u8);
 
@@ -42,38 +137,93 @@ pub trait Port {
     fn read_ppu_data_reg(&mut self) -> u8;
     fn write_ppu_data_reg(&mut self, value: u8);
 
+    fn read_sprite_data(&mut self) -> u8;
     fn write_sprite_data(&mut self, value: u8);
     fn write_sprite_addr_reg(&mut self, value: u8);
+
+    fn read_open_bus(&mut self) -> u8;
+    fn write_open_bus(&mut self, value: u8);
 }
 impl Ppu {
     pub fn new() -> Self {
         Self {
-            ppux: 341,
+            ppux: 0,
             line: 0,
+            odd_frame: false,
+            region: rom::Region::NTSC,
+            scanlines: 262,
+            vblank_line: 241,
+            dots_per_5_cycles: 15,
+            dot_remainder: 0,
             regs: (0..8).map(|x| 0).collect(),
             imgdata: vec![0; 256 * 240 * 3],
+            idxdata: vec![0; 256 * 240],
             imgok: false,
-            imgidx: 0,
-            rcount: 0,
+            nmi_pending: false,
+            vbl_suppressed: false,
 
-            sprite_zero: false,
-            scroll_reg_flg: false,
+            ppu_addr: 0,
             ppu_addr_buffer: 0,
             h_scroll_val: 0,
-            ppu_addr_reg_flg: false,
-            ppu_addr: 0,
+            write_toggle: false,
             ppu_read_buffer: 0,
+
+            io_latch: 0,
+            io_refresh: vec![0; 8],
+            frame: 0,
+            decay_frames: 36,
+
             screen_mirroring: Mirroring::HORIZONTAL,
 
             vram: vec![vec![0; 4096]; 16],
             vrams: vec![vec![0; 1024]; 16],
+            nametables: vec![8, 9, 8, 9],
+
+            bg_next_name: 0,
+            bg_next_attr: 0,
+            bg_next_low: 0,
+            bg_next_high: 0,
+            bg_shift_low: 0,
+            bg_shift_high: 0,
+            at_shift_low: 0,
+            at_shift_high: 0,
+
+            secondary_oam: vec![0xff; 32],
+            eval_addr: 0,
+            eval_copy: 0,
+            eval_count: 0,
+            eval_data: 0,
+            eval_done: false,
+            eval_zero: false,
 
-            bg_line_buffer: (0..264).map(|x| 0).collect(),
-            sp_line_buffer: (0..264).map(|x| 0).collect(),
+            sp_count: 0,
+            sp_zero: false,
+            sp_low: vec![0; 64],
+            sp_high: vec![0; 64],
+            sp_attr: vec![0; 64],
+            sp_x: vec![0; 64],
+            sp_index: vec![0; 64],
+            secondary_index: vec![0; 8],
 
             palette: (0..33).map(|x| 0x0f).collect(),
+            palette_table: build_emphasis_table(PALLETE_TABLE),
             sprite_ram: (0..0x100).map(|x| 0).collect(),
-            spbit_pattern: vec![vec![vec![0; 8]; 256]; 256],
+            no_sprite_limit: false,
+
+            show_bg: true,
+            show_sprites: true,
+            hidden_sprites: 0,
+
+            record_sources: false,
+            sources: Vec::new(),
+            bg_next_source: TilePixel::new(),
+            bg_sources: [TilePixel::new(); 2],
+            bg_shift_count: 0,
+            sp_sources: vec![TilePixel::new(); 64],
+
+            watch_patterns: false,
+            pattern_fetches: VecDeque::new(),
+            dots_due: 0,
         }
     }
     pub fn init(&mut self) {
@@ -81,87 +231,81 @@ impl Ppu {
     }
     pub fn start(&mut self, rom: &mut rom::Rom) {
         println!("ppu start");
-        self.crate_spbit_array();
         self.palette = [0x0f; 33].to_vec();
         self.sprite_ram = [0; 0x100].to_vec();
-        self.bg_line_buffer = [0; 264].to_vec();
-        self.sp_line_buffer = [0; 264].to_vec();
 
-        self.screen_mirroring = rom.screen_mirroring.clone();
-        match self.screen_mirroring {
-            Mirroring::VERTICAL => {
-                self.set_mode_mirror(false, rom);
-            }
-            Mirroring::HORIZONTAL => {
-                self.set_mode_mirror(true, rom);
-            }
-            Mirroring::FOUR_SCREEN => {
-                self.init_mirrors(0, 1, 2, 3, rom);
-            }
-        }
+        self.set_mirroring(rom.screen_mirroring.clone());
+        self.set_region(rom.region);
 
-        self.ppux = 341;
+        self.ppux = 0;
         self.line = 0;
-        self.sprite_zero = false;
+        self.odd_frame = false;
         self.imgok = false;
     }
     pub fn reset(&mut self) {
-        self.imgidx = 0;
-        self.scroll_reg_flg = false;
-        self.ppu_addr_reg_flg = false;
+        self.write_toggle = false;
         self.ppu_addr_buffer = 0;
         self.ppu_read_buffer = 0;
         self.ppu_addr = 0;
         self.h_scroll_val = 0;
-        self.ppux = 341;
+        self.ppux = 0;
         self.line = 0;
-        self.sprite_zero = false;
+        self.odd_frame = false;
+        self.dot_remainder = 0;
+        self.nmi_pending = false;
+        self.vbl_suppressed = false;
+        self.sp_count = 0;
         self.imgok = false;
-        self.clear_arryas();
-    }
-    fn crate_spbit_array(&mut self) {
-        for i in 0..256 {
-            for j in 0..256 {
-                for k in 0..8 {
-                    let lval = (((i << k) & 0x80) >> 7);
-                    let rval = (((j << k) & 0x80) >> 6);
-                    let val = (lval | rval);
-                    self.spbit_pattern[i][j][k] = val as u8;
-                }
+    }
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        match mirroring {
+            Mirroring::VERTICAL => {
+                self.set_mode_mirror(false);
+            }
+            Mirroring::HORIZONTAL => {
+                self.set_mode_mirror(true);
+            }
+            Mirroring::FOUR_SCREEN => {
+                self.init_mirrors(0, 1, 2, 3);
+            }
+            Mirroring::SINGLE_SCREEN_LOWER => {
+                self.init_mirrors(0, 0, 0, 0);
+            }
+            Mirroring::SINGLE_SCREEN_UPPER => {
+                self.init_mirrors(1, 1, 1, 1);
             }
         }
+        self.screen_mirroring = mirroring;
     }
-    fn clear_arryas(&mut self) {
-        // self.regs = (0..8).map(|x| 0).collect();
-        // self.vram = vec![vec![0; 4096]; 16];
-        // self.vrams = vec![vec![0; 1024]; 16];
-        // self.bg_line_buffer = (0..264).map(|x| 0).collect();
-        // self.sp_line_buffer = (0..264).map(|x| 0).collect();
-        // self.palette = (0..33).map(|x| 0x0f).collect();
-        // self.sprite_ram =  (0..0x100).map(|x| 0).collect();
-    }
-    fn set_mode_mirror(&mut self, value: bool, rom: &mut rom::Rom) {
+    pub fn set_mode_mirror(&mut self, value: bool) {
         if (value) {
-            self.init_mirrors(0, 0, 1, 1, rom);
+            self.init_mirrors(0, 0, 1, 1);
         } else {
-            self.init_mirrors(0, 1, 0, 1, rom);
+            self.init_mirrors(0, 1, 0, 1);
         }
     }
-    fn init_mirrors(
-        &mut self,
-        value0: isize,
-        value1: isize,
-        value2: isize,
-        value3: isize,
-        rom: &mut rom::Rom,
-    ) {
-        self.set_chr_rom_data1k(8, value0 + 8 + 0x0100, rom);
-        self.set_chr_rom_data1k(9, value1 + 8 + 0x0100, rom);
-        self.set_chr_rom_data1k(10, value2 + 8 + 0x0100, rom);
-        self.set_chr_rom_data1k(11, value3 + 8 + 0x0100, rom);
+    pub fn init_mirrors(&mut self, value0: usize, value1: usize, value2: usize, value3: usize) {
+        self.set_nametable(0, value0 + 8);
+        self.set_nametable(1, value1 + 8);
+        self.set_nametable(2, value2 + 8);
+        self.set_nametable(3, value3 + 8);
+    }
+    // Maps one of the 16 1K VRAM pages into nametable slot 0-3. Pages 8 and 9
+    // are the console's CIRAM, 10 and 11 the extra cartridge VRAM of
+    // four-screen boards; the rest are free for mappers to use.
+    pub fn set_nametable(&mut self, slot: usize, page: usize) {
+        self.nametables[slot & 0x03] = page & 0x0f;
+    }
+    fn read_nametable(&mut self, addr: usize) -> u8 {
+        return self.vrams[self.nametables[(addr >> 10) & 0x03]][addr & 0x03ff];
+    }
+    fn write_nametable(&mut self, addr: usize, value: u8) {
+        self.vrams[self.nametables[(addr >> 10) & 0x03]][addr & 0x03ff] = value;
     }
-    fn set_chr_rom_data1k(&mut self, mut page: isize, romPage: isize, rom: &mut rom::Rom) {
-        if (romPage >= 0x0100) {
+    pub fn set_chr_rom_data1k(&mut self, mut page: isize, romPage: isize, rom: &mut rom::Rom) {
+        if (page >= 8) {
+            self.set_nametable((page - 8) as usize, (romPage & 0x0f) as usize);
+        } else if (romPage >= 0x0100) {
             rom.chrrom_state[page as usize] = romPage as u8;
             self.vram[page as usize] = self.vrams[(romPage & 0xff) as usize].to_vec();
         } else {
@@ -200,248 +344,588 @@ impl Ppu {
             self.set_chr_rom_data1k(i, num + i, rom);
         }
     }
-    pub fn run(&mut self, cpuclock: usize, irq: &mut irq::Irq) {
-        let mut tmpx = self.ppux;
-        self.ppux += cpuclock * 3;
+    pub fn set_region(&mut self, region: rom::Region) {
+        self.region = region;
+        match region {
+            rom::Region::NTSC => {
+                self.scanlines = 262;
+                self.vblank_line = 241;
+                self.dots_per_5_cycles = 15;
+                self.decay_frames = 36;
+            }
+            rom::Region::PAL => {
+                self.scanlines = 312;
+                self.vblank_line = 241;
+                self.dots_per_5_cycles = 16;
+                self.decay_frames = 30;
+            }
+            rom::Region::DENDY => {
+                self.scanlines = 312;
+                self.vblank_line = 291;
+                self.dots_per_5_cycles = 15;
+                self.decay_frames = 30;
+            }
+        }
+        if self.line >= self.scanlines {
+            self.line = 0;
+        }
+    }
+    pub fn set_bg_visible(&mut self, visible: bool) {
+        self.show_bg = visible;
+    }
+    pub fn is_bg_visible(&mut self) -> bool {
+        return self.show_bg;
+    }
+    pub fn set_sprites_visible(&mut self, visible: bool) {
+        self.show_sprites = visible;
+    }
+    pub fn is_sprites_visible(&mut self) -> bool {
+        return self.show_sprites;
+    }
+    // Hides or shows a single OAM entry (0-63) in the output.
+    pub fn set_sprite_hidden(&mut self, index: usize, hidden: bool) {
+        if hidden {
+            self.hidden_sprites |= 1 << (index & 0x3f);
+        } else {
+            self.hidden_sprites &= !(1 << (index & 0x3f));
+        }
+    }
+    pub fn is_sprite_hidden(&mut self, index: usize) -> bool {
+        return (self.hidden_sprites >> (index & 0x3f)) & 0x01 != 0;
+    }
+    pub fn show_all_sprites(&mut self) {
+        self.hidden_sprites = 0;
+    }
+    // Takes either 64 base colours or all 512 emphasis combinations.
+    pub fn set_palette_table(&mut self, table: Vec<(u8, u8, u8)>) {
+        if table.len() == 64 {
+            self.palette_table = build_emphasis_table(&table);
+        } else if table.len() == 512 {
+            self.palette_table = table;
+        }
+    }
+    // Queues the dots of `cpuclock` CPU cycles for `run_dots`.
+    pub fn add_cycles(&mut self, cpuclock: usize) {
+        // PAL runs 3.2 dots per CPU cycle, so count in fifths of a cycle.
+        let total = cpuclock * self.dots_per_5_cycles + self.dot_remainder;
+        self.dot_remainder = total % 5;
+        self.dots_due += total / 5;
+    }
+    // Steps the queued dots. With `watch_patterns` set it returns early with
+    // the address of each pattern fetch, oldest first, before stepping on;
+    // call again to carry on.
+    pub fn run_dots(&mut self) -> Option<usize> {
+        loop {
+            if let Some(addr) = self.pattern_fetches.pop_front() {
+                return Some(addr);
+            }
+            if self.dots_due == 0 {
+                return None;
+            }
+            self.dots_due -= 1;
+            self.step();
+        }
+    }
+    // Called once the CPU finishes an instruction; raises the NMI if
+    // vblank or a $2000 write asked for one.
+    pub fn raise_nmi(&mut self, irq: &mut irq::Irq) {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            irq.set_nmi(true);
+        }
+    }
+    fn step(&mut self) {
+        let visible = self.line < 240;
+        let prerender = self.is_prerender_line();
 
-        while (341 <= self.ppux) {
-            self.ppux -= 341;
-            self.line += 1;
-            tmpx = 0;
-            self.sprite_zero = false;
+        // The background shifters move (and reload at tile edges) before the
+        // dot's pixel is taken from them.
+        if (visible || prerender) && self.is_rendering_enable() {
+            self.render_dot();
+        }
+        if visible && 1 <= self.ppux && self.ppux <= 256 {
+            self.render_pixel();
+        }
 
-            if self.line < 240 {
-                self.render_frame();
-            } else if self.line == 240 {
-                self.in_vblank(irq);
-                continue;
-            } else if self.line == 262 {
-                self.post_render();
+        if self.line == self.vblank_line && self.ppux == 1 {
+            if !self.vbl_suppressed {
+                self.regs[0x02] |= 0x80;
+                if (self.regs[0x00] & 0x80) == 0x80 {
+                    self.nmi_pending = true;
+                }
             }
+            self.vbl_suppressed = false;
+            self.imgok = true;
+            self.frame += 1;
+        } else if prerender && self.ppux == 1 {
+            self.regs[0x02] &= 0x1f;
         }
 
-        if (self.sprite_zero && (self.regs[0x02] & 0x40) != 0x40) {
-            let i = if self.ppux > 255 { 255 } else { self.ppux };
-            while tmpx <= i {
-                if (self.sp_line_buffer[tmpx] == 0) {
-                    self.regs[0x02] |= 0x40;
-                    break;
-                }
-                tmpx += 1;
+        // On NTSC the pre-render line is one dot shorter on odd frames while
+        // rendering is enabled.
+        if prerender
+            && self.ppux == 339
+            && self.odd_frame
+            && self.region == rom::Region::NTSC
+            && self.is_rendering_enable()
+        {
+            self.ppux = 340;
+        }
+        self.ppux += 1;
+        if self.ppux == 341 {
+            self.ppux = 0;
+            self.line += 1;
+            if self.line == self.scanlines {
+                self.line = 0;
+                self.odd_frame = !self.odd_frame;
             }
         }
     }
-    fn render_frame(&mut self) {
-        if self.is_screen_enable() || self.is_sprite_enable() {
-            self.ppu_addr = (self.ppu_addr & 0xfbe0) | (self.ppu_addr_buffer & 0x041f);
+    fn render_dot(&mut self) {
+        let dot = self.ppux;
 
-            if (8 <= self.line && self.line < 232) {
-                self.build_bg();
-                self.build_sp_line();
-                for p in (0..256) {
-                    let idx = self.palette[self.bg_line_buffer[p] as usize];
-                    let pal = PALLETE_TABLE[idx as usize];
-                    self.set_img_data(pal);
+        if (2 <= dot && dot <= 257) || (322 <= dot && dot <= 337) {
+            self.shift_bg();
+        }
+        if (1 <= dot && dot <= 257) || (321 <= dot && dot <= 337) {
+            match (dot - 1) & 0x07 {
+                0 => {
+                    // Reloads happen at dots 9-257 and 329/337; the fetches
+                    // at 1 and 321 only start a new tile.
+                    if dot != 1 && dot != 321 {
+                        self.load_bg_shifters();
+                    }
+                    self.bg_next_name = self.read_nametable(0x2000 | (self.ppu_addr & 0x0fff));
                 }
-            } else {
-                for p in (0..264) {
-                    self.bg_line_buffer[p] = 0x10;
+                2 => {
+                    let v = self.ppu_addr;
+                    let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.bg_next_attr = (self.read_nametable(addr) >> shift) & 0x03;
+                }
+                4 => {
+                    let addr = self.bg_pattern_addr();
+                    self.bg_next_low = self.read_pattern(addr);
+                    if self.record_sources {
+                        self.bg_next_source = self.tile_source(addr, self.bg_next_attr);
+                    }
+                }
+                6 => {
+                    let addr = self.bg_pattern_addr() + 8;
+                    self.bg_next_high = self.read_pattern(addr);
                 }
-                self.build_sp_line();
+                7 => {
+                    self.increment_x();
+                }
+                _ => {}
             }
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            self.ppu_addr = (self.ppu_addr & 0xfbe0) | (self.ppu_addr_buffer & 0x041f);
+        }
+        if dot == 338 || dot == 340 {
+            self.bg_next_name = self.read_nametable(0x2000 | (self.ppu_addr & 0x0fff));
+        }
+        if self.is_prerender_line() && 280 <= dot && dot <= 304 {
+            self.ppu_addr = (self.ppu_addr & 0x841f) | (self.ppu_addr_buffer & 0x7be0);
+        }
 
-            if ((self.ppu_addr & 0x7000) == 0x7000) {
-                self.ppu_addr &= 0x8fff;
-
-                if ((self.ppu_addr & 0x03e0) == 0x03a0) {
-                    self.ppu_addr = (self.ppu_addr ^ 0x0800) & 0xfc1f;
-                } else if ((self.ppu_addr & 0x03e0) == 0x03e0) {
-                    self.ppu_addr &= 0xfc1f;
-                } else {
-                    self.ppu_addr += 0x0020;
-                }
-            } else {
-                self.ppu_addr += 0x1000;
+        // With OAMADDR at 8 or above when rendering starts, the eight bytes
+        // of its row are copied over the first eight bytes of OAM.
+        if self.is_prerender_line() && dot == 1 && self.regs[0x03] >= 8 {
+            let row = (self.regs[0x03] & 0xf8) as usize;
+            for i in 0..8 {
+                self.sprite_ram[i] = self.sprite_ram[row + i];
             }
-        } else if (8 <= self.line && self.line < 232) {
-            let pal = PALLETE_TABLE[self.palette[0x10] as usize];
-            for x in (0..256) {
-                self.set_img_data(pal);
+        }
+        if self.line < 240 {
+            if 1 <= dot && dot <= 64 {
+                if (dot & 0x01) == 0 {
+                    self.secondary_oam[(dot >> 1) - 1] = 0xff;
+                }
+            } else if 65 <= dot && dot <= 256 {
+                self.evaluate_sprites(dot);
             }
         }
+        if 257 <= dot && dot <= 320 {
+            self.regs[0x03] = 0;
+            self.fetch_sprites(dot);
+        }
     }
-    fn build_bg(&mut self) {
-        if ((self.regs[0x01] & 0x08) != 0x08) {
-            for p in 0..264 {
-                self.bg_line_buffer[p] = 0x10;
-            }
+    fn bg_pattern_addr(&mut self) -> usize {
+        let table = ((self.regs[0x00] & 0x10) as usize) << 8;
+        return table + ((self.bg_next_name as usize) << 4) + ((self.ppu_addr >> 12) & 0x07);
+    }
+    fn shift_bg(&mut self) {
+        self.bg_shift_count += 1;
+        self.bg_shift_low <<= 1;
+        self.bg_shift_high <<= 1;
+        self.at_shift_low <<= 1;
+        self.at_shift_high <<= 1;
+    }
+    fn load_bg_shifters(&mut self) {
+        self.bg_sources[0] = self.bg_sources[1];
+        self.bg_sources[1] = self.bg_next_source;
+        self.bg_shift_count = 0;
+        self.bg_shift_low = (self.bg_shift_low & 0xff00) | self.bg_next_low as u16;
+        self.bg_shift_high = (self.bg_shift_high & 0xff00) | self.bg_next_high as u16;
+        let low = if (self.bg_next_attr & 0x01) != 0 {
+            0xff
+        } else {
+            0x00
+        };
+        let high = if (self.bg_next_attr & 0x02) != 0 {
+            0xff
+        } else {
+            0x00
+        };
+        self.at_shift_low = (self.at_shift_low & 0xff00) | low;
+        self.at_shift_high = (self.at_shift_high & 0xff00) | high;
+    }
+    fn increment_x(&mut self) {
+        if (self.ppu_addr & 0x001f) == 0x001f {
+            self.ppu_addr = (self.ppu_addr & !0x001f) ^ 0x0400;
+        } else {
+            self.ppu_addr += 1;
+        }
+    }
+    fn increment_y(&mut self) {
+        if (self.ppu_addr & 0x7000) != 0x7000 {
+            self.ppu_addr += 0x1000;
             return;
         }
-        self.build_bg_line();
-        if ((self.regs[0x01] & 0x02) != 0x02) {
-            for x in 0..8 {
-                self.bg_line_buffer[x] = 0x10;
-            }
+        self.ppu_addr &= 0x8fff;
+        if (self.ppu_addr & 0x03e0) == 0x03a0 {
+            self.ppu_addr = (self.ppu_addr ^ 0x0800) & 0xfc1f;
+        } else if (self.ppu_addr & 0x03e0) == 0x03e0 {
+            self.ppu_addr &= 0xfc1f;
+        } else {
+            self.ppu_addr += 0x0020;
         }
     }
-    fn build_bg_line(&mut self) {
-        let nameaddr = 0x2000 | (self.ppu_addr & 0x0fff);
-        let tableaddr =
-            ((self.ppu_addr & 0x7000) >> 12) | (((self.regs[0x00] & 0x10) as usize) << 8);
-        let mut name_addr_h = nameaddr >> 10;
-        let mut name_addr_l = nameaddr & 0x03ff;
-        let mut pre_name_addrh = name_addr_h;
-        let mut s = self.h_scroll_val;
-        let mut q = 0;
-
-        for p in 0..33 {
-            let vram = &self.vram[pre_name_addrh];
-            let mut ptndist = ((vram[name_addr_l] as usize) << 4) | tableaddr;
-            let vvram = &self.vram[(ptndist >> 10) as usize];
-            ptndist &= 0x03ff;
-
-            let lval = (name_addr_l & 0x0380) >> 4;
-            let rval = ((name_addr_l & 0x001c) >> 2) + 0x03c0;
-
-            let lval2 = (name_addr_l & 0x0040) >> 4;
-            let rval2 = name_addr_l & 0x0002;
-            let attr = (((vram[lval | rval] as usize) << 2) >> (lval2 | rval2)) & 0x0c;
-
-            let spbidx1 = vvram[ptndist as usize];
-            let spbidx2 = vvram[(ptndist + 8) as usize];
-            let ptn = &self.spbit_pattern[spbidx1 as usize][spbidx2 as usize];
+    // Odd dots read primary OAM, even dots write the result into secondary
+    // OAM. The scan starts wherever OAMADDR points, and whatever it checks
+    // first counts as sprite 0. Once eight sprites are found the hardware
+    // bug kicks in: the byte offset is bumped along with the sprite number,
+    // so tiles, attributes and X positions get checked as Y coordinates.
+    fn evaluate_sprites(&mut self, dot: usize) {
+        if dot == 65 {
+            self.eval_addr = self.regs[0x03] as usize;
+            self.eval_copy = 0;
+            self.eval_count = 0;
+            self.eval_done = false;
+            self.eval_zero = false;
+        }
+        if (dot & 0x01) == 1 {
+            self.eval_data = self.sprite_ram[self.eval_addr & 0xff];
+            return;
+        }
+        if self.eval_done {
+            return;
+        }
 
-            while s < 8 {
-                let idx = ptn[s] | attr as u8;
-                self.bg_line_buffer[q] = PALLETE[idx as usize];
-                q += 1;
-                s += 1;
+        if self.eval_count < 8 {
+            self.secondary_oam[(self.eval_count << 2) + self.eval_copy] = self.eval_data;
+            if self.eval_copy == 0 && !self.sprite_in_range(self.eval_data) {
+                self.eval_addr += 4;
+                self.eval_done = self.eval_addr >= 0x100;
+                return;
             }
-            s = 0;
-
-            if ((name_addr_l & 0x001f) == 0x001f) {
-                name_addr_l &= 0xffe0;
-                name_addr_h ^= 0x01;
-                pre_name_addrh = name_addr_h;
-            } else {
-                name_addr_l += 1;
+            if self.eval_copy == 0 {
+                self.secondary_index[self.eval_count] = (self.eval_addr >> 2) as u8;
+                self.eval_zero |= dot == 66;
+            }
+            self.eval_addr += 1;
+            self.eval_copy += 1;
+            if self.eval_copy == 4 {
+                self.eval_copy = 0;
+                self.eval_count += 1;
+                self.eval_done = self.eval_addr >= 0x100;
             }
+        } else if self.sprite_in_range(self.eval_data) {
+            self.regs[0x02] |= 0x20;
+            self.eval_done = true;
+        } else {
+            let n = (self.eval_addr >> 2) + 1;
+            self.eval_addr = (n << 2) | ((self.eval_addr + 1) & 0x03);
+            self.eval_done = n >= 64;
         }
     }
-    fn build_sp_line(&mut self) {
-        let spclip = if (self.regs[0x01] & 0x04) == 0x04 {
-            0
+    fn sprite_in_range(&mut self, y: u8) -> bool {
+        let row = self.line as isize - y as isize;
+        return 0 <= row && row < self.is_bigsize() as isize;
+    }
+    fn fetch_sprites(&mut self, dot: usize) {
+        if dot == 257 {
+            if self.is_prerender_line() {
+                self.eval_count = 0;
+                self.eval_zero = false;
+            }
+            self.sp_count = self.eval_count;
+            self.sp_zero = self.eval_zero;
+        }
+        if dot == 320 && self.no_sprite_limit && self.eval_count == 8 && self.line < 240 {
+            self.add_extra_sprites();
+        }
+        let slot = (dot - 257) >> 3;
+        let step = (dot - 257) & 0x07;
+        if step != 4 && step != 6 {
+            return;
+        }
+
+        // Empty slots still fetch tile $FF, which mappers watching the
+        // pattern bus rely on.
+        let (y, tile, attr, x) = if slot < self.sp_count {
+            let base = slot << 2;
+            (
+                self.secondary_oam[base],
+                self.secondary_oam[base + 1],
+                self.secondary_oam[base + 2],
+                self.secondary_oam[base + 3],
+            )
         } else {
-            8
+            (0xff, 0xff, 0xff, 0xff)
         };
-
-        if ((self.regs[0x01] & 0x10) == 0x10) {
-            for p in 0..264 {
-                self.sp_line_buffer[p] = 256;
+        let addr = self.sprite_pattern_addr(y, tile, attr);
+        if step == 4 {
+            let mut low = self.read_pattern(addr);
+            if slot >= self.sp_count {
+                low = 0;
+            } else if (attr & 0x40) == 0x40 {
+                low = low.reverse_bits();
             }
+            self.sp_low[slot] = low;
+            self.sp_attr[slot] = attr;
+            self.sp_x[slot] = x;
+            self.sp_index[slot] = self.secondary_index[slot & 0x07];
+            if self.record_sources {
+                self.sp_sources[slot] = self.tile_source(addr, 0x04 | (attr & 0x03));
+            }
+        } else {
+            let mut high = self.read_pattern(addr + 8);
+            if slot >= self.sp_count {
+                high = 0;
+            } else if (attr & 0x40) == 0x40 {
+                high = high.reverse_bits();
+            }
+            self.sp_high[slot] = high;
+        }
+    }
+    fn sprite_pattern_addr(&mut self, y: u8, tile: u8, attr: u8) -> usize {
+        let size = self.is_bigsize();
+        let mut row = (self.line as isize - y as isize) as usize & (size - 1);
+        if (attr & 0x80) == 0x80 {
+            row = size - 1 - row;
+        }
+        if size == 16 {
+            let table = ((tile & 0x01) as usize) << 12;
+            return table + (((tile & 0xfe) as usize + (row >> 3)) << 4) + (row & 0x07);
+        }
+        let table = ((self.regs[0x00] & 0x08) as usize) << 9;
+        return table + ((tile as usize) << 4) + row;
+    }
+    // With the sprite limit off, sprites past the eighth on the line are
+    // appended behind the real ones. Evaluation and the overflow flag are
+    // untouched, and the patterns are read without involving the mapper.
+    fn add_extra_sprites(&mut self) {
+        let mut found = 0;
+        for n in 0..64 {
+            let base = n << 2;
+            let y = self.sprite_ram[base];
+            if !self.sprite_in_range(y) {
+                continue;
+            }
+            found += 1;
+            if found <= 8 {
+                continue;
+            }
+            let tile = self.sprite_ram[base + 1];
+            let attr = self.sprite_ram[base + 2] & 0xe3;
+            let addr = self.sprite_pattern_addr(y, tile, attr);
+            let mut low = self.vram[addr >> 10][addr & 0x03ff];
+            let mut high = self.vram[(addr + 8) >> 10][(addr + 8) & 0x03ff];
+            if (attr & 0x40) == 0x40 {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            let slot = self.sp_count;
+            self.sp_low[slot] = low;
+            self.sp_high[slot] = high;
+            self.sp_attr[slot] = attr;
+            self.sp_x[slot] = self.sprite_ram[base + 3];
+            self.sp_index[slot] = n as u8;
+            if self.record_sources {
+                self.sp_sources[slot] = self.tile_source(addr, 0x04 | (attr & 0x03));
+            }
+            self.sp_count += 1;
+        }
+    }
+    fn render_pixel(&mut self) {
+        let x = self.ppux - 1;
+        let mut bg = 0;
+        let mut bg_pal = 0;
+        if self.is_screen_enable() && (x >= 8 || (self.regs[0x01] & 0x02) == 0x02) {
+            let mux = 0x8000 >> self.h_scroll_val;
+            bg = (((self.bg_shift_high & mux) != 0) as u8) << 1
+                | ((self.bg_shift_low & mux) != 0) as u8;
+            bg_pal = (((self.at_shift_high & mux) != 0) as u8) << 1
+                | ((self.at_shift_low & mux) != 0) as u8;
+        }
 
-            let spptableaddr = ((self.regs[0x00] & 0x08) as usize) << 9;
-            let mut count = 0;
-            let bzsize = self.is_bigsize();
-
-            for i in (0..=252).step_by(4) {
-                let isy = (self.sprite_ram[i] as usize + 1);
-                if isy > self.line || (isy + bzsize <= self.line) {
+        let mut sp = 0;
+        let mut sp_attr = 0;
+        let mut sp_slot = 0;
+        if self.is_sprite_enable() && (x >= 8 || (self.regs[0x01] & 0x04) == 0x04) {
+            for i in 0..self.sp_count {
+                let offset = x as isize - self.sp_x[i] as isize;
+                if !(0..8).contains(&offset) {
                     continue;
                 }
-
-                if (i == 0) {
-                    self.sprite_zero = true;
+                let bit = 7 - offset as usize;
+                let value =
+                    (((self.sp_high[i] >> bit) & 0x01) << 1) | ((self.sp_low[i] >> bit) & 0x01);
+                if value == 0 {
+                    continue;
                 }
-
-                count += 1;
-                if (count == 9) {
-                    break;
+                if i == 0 && self.sp_zero && bg != 0 && x != 255 {
+                    self.regs[0x02] |= 0x40;
                 }
-
-                let attr = self.sprite_ram[i + 2];
-                let attribute = (((attr & 0x03) as usize) << 2) | 0x10;
-                let bgsp = (attr & 0x20) == 0x00;
-
-                let mut x = (self.sprite_ram[i + 3]) as usize;
-                let mut ex = x + 8;
-                if ex > 256 {
-                    ex = 256;
+                if !self.show_sprites || (self.hidden_sprites >> self.sp_index[i]) & 0x01 != 0 {
+                    continue;
                 }
+                sp = value;
+                sp_attr = self.sp_attr[i];
+                sp_slot = i;
+                break;
+            }
+        }
 
-                let iy = if (attr & 0x80) == 0x80 {
-                    bzsize - 1 - (self.line - isy)
-                } else {
-                    self.line - isy
-                };
-
-                let lval = ((self.sprite_ram[i + 1] as usize) << 4) + spptableaddr;
-                let rval = ((self.sprite_ram[i + 1] as usize & 0xfe) << 4)
-                    + ((self.sprite_ram[i + 1] as usize & 0x01) << 12);
-                let sval = if bzsize == 8 { lval } else { rval };
-                let tilenum = ((iy & 0x08) << 1) + (iy & 0x07) + sval;
-                let tlow = tilenum & 0x03ff;
-
-                let mut is: isize;
-                let ia: isize;
-                if ((attr & 0x40) == 0x00) {
-                    is = 0;
-                    ia = 1;
-                } else {
-                    is = 7;
-                    ia = -1;
-                }
+        if !self.show_bg {
+            bg = 0;
+        }
+        if self.record_sources {
+            self.record_source(x, bg, sp, sp_attr, sp_slot);
+        }
+        let index = if sp != 0 && (bg == 0 || (sp_attr & 0x20) == 0) {
+            0x10 | ((sp_attr & 0x03) << 2) | sp
+        } else if bg != 0 {
+            (bg_pal << 2) | bg
+        } else {
+            0
+        };
 
-                let ptnidxl = self.vram[tilenum >> 10][tlow];
-                let ptnidxr = self.vram[tilenum >> 10][tlow + 8];
-                let ptn = &self.spbit_pattern[ptnidxl as usize][ptnidxr as usize];
-
-                while x < ex {
-                    let tptn = ptn[is as usize];
-                    if (tptn != 0x00 && (self.sp_line_buffer[x as usize] == 256)) {
-                        self.sp_line_buffer[x as usize] = i as u16;
-                        if (x >= spclip && (bgsp || self.bg_line_buffer[x as usize] == 0x10)) {
-                            self.bg_line_buffer[x as usize] = tptn | attribute as u8;
-                        }
-                    }
-                    x += 1;
-                    is += ia;
-                }
-            }
+        let mut color = self.palette[index as usize] as usize;
+        if (self.regs[0x01] & 0x01) == 0x01 {
+            color &= 0x30;
+        }
+        let emphasis = self.emphasis() as usize;
+        let pal = self.palette_table[(emphasis << 6) | color];
+        self.idxdata[self.line * 256 + x] = ((emphasis << 6) | color) as u16;
+        let idx = (self.line * 256 + x) * 3;
+        self.imgdata[idx] = pal.0;
+        self.imgdata[idx + 1] = pal.1;
+        self.imgdata[idx + 2] = pal.2;
+    }
+    // Tile and row behind a pattern fetch at `addr`, read without side
+    // effects.
+    fn tile_source(&mut self, addr: usize, palette: u8) -> TilePixel {
+        let base = addr & !0x0f;
+        let mut source = TilePixel::new();
+        for i in 0..16 {
+            source.tile[i] = self.vram[(base + i) >> 10][(base + i) & 0x03ff];
+        }
+        source.palette = palette;
+        source.y = (addr & 0x07) as u8;
+        return source;
+    }
+    fn record_source(&mut self, x: usize, bg: u8, sp: u8, sp_attr: u8, sp_slot: usize) {
+        if self.sources.len() != 256 * 240 {
+            let empty = PixelSource {
+                bg: TilePixel::new(),
+                sprite: TilePixel::new(),
+            };
+            self.sources = vec![empty; 256 * 240];
+        }
+        // The pixel under fine x has been shifted `bg_shift_count` times
+        // since the last reload; past column 7 it belongs to the next tile.
+        let column = self.h_scroll_val + self.bg_shift_count;
+        let mut source = self.bg_sources[column >> 3 & 0x01];
+        source.x = (column & 0x07) as u8;
+        source.value = bg;
 
-            if (8 <= count) {
-                self.regs[0x02] |= 0x20;
+        let mut sprite = TilePixel::new();
+        if sp != 0 {
+            sprite = self.sp_sources[sp_slot];
+            let offset = (x - self.sp_x[sp_slot] as usize) as u8;
+            sprite.x = if (sp_attr & 0x40) == 0x40 {
+                7 - offset
             } else {
-                self.regs[0x02] &= 0xdf;
-            }
+                offset
+            };
+            sprite.attr = sp_attr;
+            sprite.value = sp;
+        }
+        self.sources[self.line * 256 + x] = PixelSource { bg: source, sprite };
+    }
+    fn read_pattern(&mut self, addr: usize) -> u8 {
+        if self.watch_patterns {
+            self.pattern_fetches.push_back(addr);
         }
+        return self.vram[addr >> 10][addr & 0x03ff];
     }
 
-    fn in_vblank(&mut self, irq: &mut irq::Irq) {
-        self.scroll_reg_flg = false;
-        self.regs[0x02] &= 0x1f;
-        self.regs[0x02] |= 0x80;
-        if ((self.regs[0x00] & 0x80) == 0x80) {
-            irq.set_nmi(true);
+    pub fn clear_img(&mut self) {
+        self.imgok = false;
+    }
+    // Reads the PPU address space without side effects, for debuggers.
+    pub fn peek(&mut self, addr: usize) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            return self.vram[addr >> 10][addr & 0x03ff];
         }
+        if addr < 0x3f00 {
+            return self.read_nametable(addr);
+        }
+        return self.palette[addr & 0x1f];
     }
-    fn post_render(&mut self) {
-        self.line = 0;
-        if (self.is_screen_enable() || self.is_sprite_enable()) {
-            self.ppu_addr = self.ppu_addr_buffer;
+    // Palette RAM colour of the background pixel at (x, y) in the 512x480
+    // space spanned by the four nametables.
+    pub fn peek_background(&mut self, x: usize, y: usize) -> u8 {
+        let x = x % 512;
+        let y = y % 480;
+        let table = ((y / 240) << 1) | (x >> 8);
+        let col = (x & 0xff) >> 3;
+        let row = (y % 240) >> 3;
+        let base = 0x2000 + table * 0x400;
+        let tile = self.peek(base + row * 32 + col) as usize;
+        let attr = self.peek(base + 0x3c0 + (row >> 2) * 8 + (col >> 2));
+        let palette = ((attr >> (((row & 0x02) << 1) | (col & 0x02))) & 0x03) as usize;
+        let addr = (((self.regs[0x00] & 0x10) as usize) << 8) + tile * 16 + (y % 240 & 0x07);
+        let bit = 7 - (x & 0x07);
+        let pixel =
+            ((self.peek(addr) >> bit) & 0x01) | (((self.peek(addr + 8) >> bit) & 0x01) << 1);
+        if pixel == 0 {
+            return self.palette[0x00];
         }
-        self.regs[0x02] &= 0x7f;
-        self.imgok = true;
+        return self.palette[palette * 4 + pixel as usize];
     }
-    fn set_img_data(&mut self, plt: (u8, u8, u8)) {
-        self.imgdata[self.imgidx] = plt.0;
-        self.imgdata[self.imgidx + 1] = plt.1;
-        self.imgdata[self.imgidx + 2] = plt.2;
-        self.imgidx += 3;
+    pub fn rgb(&mut self, color: u8) -> (u8, u8, u8) {
+        return self.palette_table[(color & 0x3f) as usize];
     }
-    pub fn clear_img(&mut self) {
-        self.imgidx = 0;
-        self.imgok = false;
+    pub fn ctrl(&mut self) -> u8 {
+        return self.regs[0x00];
+    }
+    // Top-left corner of the next frame's background in the 512x480
+    // nametable space, taken from t and fine x.
+    pub fn scroll_origin(&mut self) -> (usize, usize) {
+        let t = self.ppu_addr_buffer;
+        let x = ((t & 0x001f) << 3) | self.h_scroll_val | ((t & 0x0400) >> 2);
+        let y = ((t & 0x03e0) >> 2) | ((t >> 12) & 0x07);
+        let y = if (t & 0x0800) != 0 { y + 240 } else { y };
+        return (x, y);
     }
     pub fn get_img_status(&mut self) -> (bool, &Vec<u8>) {
         if self.imgok {
@@ -450,24 +934,62 @@ impl Ppu {
             return (false, &self.imgdata);
         }
     }
-    fn is_screen_enable(&mut self) -> bool {
+    pub fn is_screen_enable(&mut self) -> bool {
         return (self.regs[0x01] & 0x08) == 0x08;
     }
     fn is_sprite_enable(&mut self) -> bool {
         return (self.regs[0x01] & 0x10) == 0x10;
     }
+    fn is_rendering_enable(&mut self) -> bool {
+        return (self.regs[0x01] & 0x18) != 0;
+    }
+    // Drives the bits in `mask` onto the I/O bus and returns the value the
+    // CPU sees, with the undriven bits coming from the decayed latch.
+    fn drive_io_latch(&mut self, value: u8, mask: u8) -> u8 {
+        for bit in 0..8 {
+            if (mask & (1 << bit)) != 0 {
+                self.io_refresh[bit] = self.frame;
+            } else if self.frame - self.io_refresh[bit] > self.decay_frames {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        return self.io_latch;
+    }
+    fn is_prerender_line(&mut self) -> bool {
+        return self.line == self.scanlines - 1;
+    }
+    // The 2C07 and the Dendy clone swap the red and green emphasis bits.
+    fn emphasis(&mut self) -> u8 {
+        let bits = self.regs[0x01] >> 5;
+        if self.region == rom::Region::NTSC {
+            return bits;
+        }
+        return (bits & 0x04) | ((bits & 0x01) << 1) | ((bits & 0x02) >> 1);
+    }
     fn is_bigsize(&mut self) -> usize {
-        let val = (if (self.regs[0x00] & 0x20) == 0x20 {
+        if (self.regs[0x00] & 0x20) == 0x20 {
             return 16;
+        }
+        return 8;
+    }
+    // $2007 accesses advance v by 1 or 32, except while rendering, where
+    // they bump coarse X and Y at once like the fetch pipeline does.
+    fn increment_ppu_addr(&mut self) {
+        if self.is_rendering_enable() && (self.line < 240 || self.is_prerender_line()) {
+            self.increment_x();
+            self.increment_y();
+        } else if (self.regs[0x00] & 0x04) == 0x04 {
+            self.ppu_addr = (self.ppu_addr + 32) & 0x7fff;
         } else {
-            return 8;
-        });
+            self.ppu_addr = (self.ppu_addr + 1) & 0x7fff;
+        }
     }
 }
 impl Port for Ppu {
     fn write_scroll_reg(&mut self, value: u8) {
         self.regs[0x05] = value;
-        if (self.scroll_reg_flg) {
+        if (self.write_toggle) {
             self.ppu_addr_buffer = (self.ppu_addr_buffer & 0x8c1f)
                 | ((value as usize & 0xf8) << 2)
                 | ((value as usize & 0x07) << 12);
@@ -475,9 +997,13 @@ impl Port for Ppu {
             self.ppu_addr_buffer = (self.ppu_addr_buffer & 0xffe0) | ((value as usize & 0xf8) >> 3);
             self.h_scroll_val = value as usize & 7;
         }
-        self.scroll_reg_flg = !self.scroll_reg_flg;
+        self.write_toggle = !self.write_toggle;
     }
     fn write_ppu_ctrl0_reg(&mut self, value: u8) {
+        // Enabling NMI during vblank raises one straight away.
+        if (self.regs[0x00] & 0x80) == 0 && (value & 0x80) != 0 && (self.regs[0x02] & 0x80) != 0 {
+            self.nmi_pending = true;
+        }
         self.regs[0x00] = value;
         self.ppu_addr_buffer = (self.ppu_addr_buffer & 0xf3ff) | (((value & 0x03) as usize) << 10);
     }
@@ -485,90 +1011,159 @@ impl Port for Ppu {
         self.regs[0x01] = value;
     }
     fn read_ppu_status_reg(&mut self) -> u8 {
-        let result = self.regs[0x02];
-        self.regs[0x02] &= 0x1f;
-        self.scroll_reg_flg = false;
-        self.ppu_addr_reg_flg = false;
+        // A read the dot before vblank starts sees it clear and stops it
+        // from being set this frame. On that dot or the next, the flag reads
+        // set but the NMI is dropped.
+        if self.line == self.vblank_line {
+            match self.ppux {
+                1 => self.vbl_suppressed = true,
+                2 | 3 => self.nmi_pending = false,
+                _ => {}
+            }
+        }
+        let result = self.drive_io_latch(self.regs[0x02], 0xe0);
+        self.regs[0x02] &= 0x7f;
+        self.write_toggle = false;
         return result;
     }
     fn write_ppu_addr_reg(&mut self, value: u8) {
         self.regs[0x06] = value;
-        if (self.ppu_addr_reg_flg) {
+        if (self.write_toggle) {
             self.ppu_addr_buffer = (self.ppu_addr_buffer & 0xff00) | value as usize;
             self.ppu_addr = self.ppu_addr_buffer;
         } else {
             self.ppu_addr_buffer = (self.ppu_addr_buffer & 0x00ff) | ((value as usize & 0x3f) << 8);
         }
-        self.ppu_addr_reg_flg = !self.ppu_addr_reg_flg;
+        self.write_toggle = !self.write_toggle;
     }
     fn read_ppu_data_reg(&mut self) -> u8 {
-        let tmp = self.ppu_read_buffer;
         let addr = self.ppu_addr & 0x3fff;
-        self.ppu_read_buffer = self.vram[(addr >> 10) as usize][addr & 0x03ff] as usize;
-
-        let val = (if (self.regs[0x00] & 0x04) == 0x04 {
-            32
+        let result = if addr < 0x3f00 {
+            let tmp = self.ppu_read_buffer as u8;
+            self.ppu_read_buffer = if addr < 0x2000 {
+                self.read_pattern(addr) as usize
+            } else {
+                self.read_nametable(addr) as usize
+            };
+            self.drive_io_latch(tmp, 0xff)
         } else {
-            1
-        });
-        self.ppu_addr = (self.ppu_addr + val) & 0xffff;
-        return tmp as u8;
+            // Palette reads are not buffered; the buffer picks up the
+            // nametable byte underneath instead. The top two bits are open bus.
+            self.ppu_read_buffer = self.read_nametable(addr & 0x2fff) as usize;
+            let mut value = self.palette[addr & 0x1f];
+            if (self.regs[0x01] & 0x01) == 0x01 {
+                value &= 0x30;
+            }
+            self.drive_io_latch(value, 0x3f)
+        };
+        self.increment_ppu_addr();
+        return result;
     }
     fn write_ppu_data_reg(&mut self, value: u8) {
         self.regs[0x07] = value;
         let tmpppu_addr = self.ppu_addr & 0x3fff;
-        self.vram[tmpppu_addr >> 10][tmpppu_addr & 0x03ff] = value;
+        self.increment_ppu_addr();
 
-        if (tmpppu_addr < 0x3000) {
-            let val = if (self.regs[0x00] & 0x04) == 0x04 {
-                32
-            } else {
-                1
-            };
-
-            self.ppu_addr = (self.ppu_addr + val) & 0xffff;
+        if (tmpppu_addr < 0x2000) {
+            self.vram[tmpppu_addr >> 10][tmpppu_addr & 0x03ff] = value;
             return;
         }
 
-        if (tmpppu_addr < 0x3eff) {
-            self.vram[(tmpppu_addr - 0x1000) >> 10][(tmpppu_addr - 0x1000) & 0x03ff] = value;
-            let val = if (self.regs[0x00] & 0x04) == 0x04 {
-                32
-            } else {
-                1
-            };
-
-            self.ppu_addr = (self.ppu_addr + val) & 0xffff;
+        if (tmpppu_addr < 0x3f00) {
+            self.write_nametable(tmpppu_addr, value);
             return;
         }
 
+        self.vram[tmpppu_addr >> 10][tmpppu_addr & 0x03ff] = value;
         let palNo = tmpppu_addr & 0x001f;
-        if (palNo == 0x00 || palNo == 0x10) {
+        if (palNo & 0x03) == 0x00 && (palNo & 0x0f) == 0x00 {
             self.palette[0x10] = (value & 0x3f);
             self.palette[0x00] = self.palette[0x10];
+        } else if (palNo & 0x03) == 0x00 {
+            self.palette[palNo & 0x0f] = value & 0x3f;
+            self.palette[palNo | 0x10] = value & 0x3f;
         } else {
             self.palette[palNo] = value & 0x3f;
         }
-        let val = if (self.regs[0x00] & 0x04) == 0x04 {
-            32
+    }
+    fn read_sprite_data(&mut self) -> u8 {
+        // While rendering, OAMDATA shows whatever sprite evaluation is
+        // reading: $FF while secondary OAM is cleared, then the byte of
+        // secondary OAM the sprite fetches are on. Each fetch reads Y, tile,
+        // attribute and X, then X again for the rest of its 8 dots.
+        let rendering = self.is_rendering_enable() && self.line < 240;
+        let value = if rendering && 1 <= self.ppux && self.ppux <= 64 {
+            0xff
+        } else if rendering && 65 <= self.ppux && self.ppux <= 256 {
+            self.eval_data
+        } else if rendering && 257 <= self.ppux && self.ppux <= 320 {
+            let slot = (self.ppux - 257) >> 3;
+            let byte = ((self.ppux - 257) & 0x07).min(3);
+            let value = self.secondary_oam[(slot << 2) + byte];
+            if byte == 2 {
+                value & 0xe3
+            } else {
+                value
+            }
         } else {
-            1
+            let addr = self.regs[0x03] as usize;
+            let mut value = self.sprite_ram[addr];
+            // Attribute bytes have no bits 2-4.
+            if (addr & 0x03) == 0x02 {
+                value &= 0xe3;
+            }
+            value
         };
-        self.ppu_addr = (self.ppu_addr + val) & 0xffff;
+        return self.drive_io_latch(value, 0xff);
     }
     fn write_sprite_data(&mut self, value: u8) {
+        // During rendering the write is dropped and only the sprite number
+        // part of OAMADDR is bumped.
+        if self.is_rendering_enable() && (self.line < 240 || self.is_prerender_line()) {
+            self.regs[0x03] = self.regs[0x03].wrapping_add(4);
+            return;
+        }
         let idx = self.regs[0x03];
         self.sprite_ram[idx as usize] = value;
-        self.regs[0x03] = (self.regs[0x03] + 1) & 0xff;
+        self.regs[0x03] = self.regs[0x03].wrapping_add(1);
     }
     fn write_sprite_addr_reg(&mut self, value: u8) {
         self.regs[0x03] = value;
     }
+    fn read_open_bus(&mut self) -> u8 {
+        return self.drive_io_latch(0, 0);
+    }
+    fn write_open_bus(&mut self, value: u8) {
+        self.drive_io_latch(value, 0xff);
+    }
 }
 
-const PALLETE: &'static [u8] = &[
-    0x10, 0x01, 0x02, 0x03, 0x10, 0x05, 0x06, 0x07, 0x10, 0x09, 0x0a, 0x0b, 0x10, 0x0d, 0x0e, 0x0f,
-];
+// Expands a 64-colour palette to all eight emphasis combinations (index
+// bits 6-8 are red, green and blue emphasis). Each emphasised channel
+// darkens the other two.
+pub fn build_emphasis_table(base: &[(u8, u8, u8)]) -> Vec<(u8, u8, u8)> {
+    let mut table = Vec::with_capacity(512);
+    for emphasis in 0..8 {
+        let mut factor = [1.0f32; 3];
+        for channel in 0..3 {
+            if (emphasis & (1 << channel)) != 0 {
+                for other in 0..3 {
+                    if other != channel {
+                        factor[other] *= 0.816;
+                    }
+                }
+            }
+        }
+        for &(r, g, b) in base.iter() {
+            table.push((
+                (r as f32 * factor[0]) as u8,
+                (g as f32 * factor[1]) as u8,
+                (b as f32 * factor[2]) as u8,
+            ));
+        }
+    }
+    return table;
+}
 
 const PALLETE_TABLE: &'static [(u8, u8, u8); 64] = &[
     (101, 101, 101),
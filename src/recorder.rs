@@ -0,0 +1,321 @@
+use crate::audio;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+const FRAME_BYTES: usize = WIDTH * HEIGHT * 3;
+// Plain AVI keeps sizes in 32 bits; stay clear of the 2GB limit.
+const AVI_LIMIT: u64 = 0x7f00_0000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    AVI,
+    Y4M,
+}
+
+// Uncompressed recordings of every emulated frame. AVI files carry RGB
+// video and a 16-bit PCM track interleaved per frame; Y4M files are 4:4:4
+// video only, with the sound written to a WAV file next to them. The frame
+// rate is the region's, as a fraction over 10000, and nothing in the files
+// depends on the wall clock, so the same input gives the same bytes.
+pub struct Recorder {
+    format: Format,
+    out: BufWriter<File>,
+    wav: Option<BufWriter<File>>,
+    frames: u32,
+    samples: u32,
+    movi_start: u64,
+    size: u64,
+    index: Vec<(&'static [u8; 4], u32, u32)>,
+}
+impl Recorder {
+    pub fn new(path: &str, frame_rate: f64) -> Result<Self, String> {
+        let format = if path.to_lowercase().ends_with(".y4m") {
+            Format::Y4M
+        } else {
+            Format::AVI
+        };
+        let rate = (frame_rate * 10000.0).round() as u32;
+        let file = File::create(path).map_err(|err| err.to_string())?;
+        let mut recorder = Self {
+            format,
+            out: BufWriter::new(file),
+            wav: None,
+            frames: 0,
+            samples: 0,
+            movi_start: 0,
+            size: 0,
+            index: Vec::new(),
+        };
+        match format {
+            Format::AVI => recorder.write_avi_header(rate)?,
+            Format::Y4M => {
+                let header = format!(
+                    "YUV4MPEG2 W{} H{} F{}:10000 Ip A1:1 C444\n",
+                    WIDTH, HEIGHT, rate
+                );
+                write(&mut recorder.out, header.as_bytes())?;
+                let wav_path = Path::new(path).with_extension("wav");
+                let wav = File::create(&wav_path).map_err(|err| err.to_string())?;
+                let mut wav = BufWriter::new(wav);
+                write_wav_header(&mut wav, 0)?;
+                recorder.wav = Some(wav);
+            }
+        }
+        return Ok(recorder);
+    }
+    pub fn add_frame(&mut self, rgb: &[u8], sound: &[i16]) -> Result<(), String> {
+        let pcm: Vec<u8> = sound.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.frames += 1;
+        self.samples += sound.len() as u32;
+        match self.format {
+            Format::AVI => {
+                if self.size + (FRAME_BYTES + pcm.len() + 32) as u64 > AVI_LIMIT {
+                    return Err("AVI size limit reached".to_string());
+                }
+                // Bottom-up BGR rows.
+                let mut frame = Vec::with_capacity(FRAME_BYTES);
+                for y in (0..HEIGHT).rev() {
+                    for pixel in rgb[y * WIDTH * 3..(y + 1) * WIDTH * 3].chunks(3) {
+                        frame.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                    }
+                }
+                self.write_chunk(b"00db", &frame)?;
+                self.write_chunk(b"01wb", &pcm)?;
+            }
+            Format::Y4M => {
+                write(&mut self.out, b"FRAME\n")?;
+                let mut planes = vec![0; FRAME_BYTES];
+                for i in 0..WIDTH * HEIGHT {
+                    let (y, u, v) = to_yuv(rgb[i * 3], rgb[i * 3 + 1], rgb[i * 3 + 2]);
+                    planes[i] = y;
+                    planes[WIDTH * HEIGHT + i] = u;
+                    planes[WIDTH * HEIGHT * 2 + i] = v;
+                }
+                write(&mut self.out, &planes)?;
+                if let Some(wav) = self.wav.as_mut() {
+                    write(wav, &pcm)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+    // Fills in the sizes and counts that are only known at the end.
+    pub fn finish(mut self) -> Result<(), String> {
+        if self.format == Format::AVI {
+            let index_offset = self.size;
+            write(&mut self.out, b"idx1")?;
+            write(&mut self.out, &(self.index.len() as u32 * 16).to_le_bytes())?;
+            for (id, offset, size) in &self.index {
+                write(&mut self.out, *id)?;
+                write(&mut self.out, &0x10u32.to_le_bytes())?;
+                write(&mut self.out, &offset.to_le_bytes())?;
+                write(&mut self.out, &size.to_le_bytes())?;
+            }
+            let end = index_offset + 8 + self.index.len() as u64 * 16;
+            self.patch(4, (end - 8) as u32)?;
+            self.patch(48, self.frames)?;
+            self.patch(140, self.frames)?;
+            self.patch(264, self.samples)?;
+            self.patch(self.movi_start - 4, (index_offset - self.movi_start) as u32)?;
+        }
+        if let Some(mut wav) = self.wav.take() {
+            wav.seek(SeekFrom::Start(0))
+                .map_err(|err| err.to_string())?;
+            write_wav_header(&mut wav, self.samples * 2)?;
+            wav.flush().map_err(|err| err.to_string())?;
+        }
+        self.out.flush().map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+    fn write_avi_header(&mut self, rate: u32) -> Result<(), String> {
+        let rate_bytes = (audio::SAMPLE_RATE * 2) as u32;
+        let mut h = Vec::new();
+        h.extend_from_slice(b"RIFF\0\0\0\0AVI ");
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&(4 + 64 + 124 + 100u32).to_le_bytes());
+        h.extend_from_slice(b"hdrl");
+
+        h.extend_from_slice(b"avih");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        let avih = [
+            (10_000_000_000u64 / rate as u64) as u32,
+            (FRAME_BYTES as u64 * rate as u64 / 10000) as u32 + rate_bytes,
+            0,
+            0x110,
+            0,
+            0,
+            2,
+            FRAME_BYTES as u32,
+            WIDTH as u32,
+            HEIGHT as u32,
+            0,
+            0,
+            0,
+            0,
+        ];
+        avih.iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+
+        // Video stream.
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&116u32.to_le_bytes());
+        h.extend_from_slice(b"strlstrh");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        h.extend_from_slice(b"vidsDIB ");
+        let strh = [
+            0,
+            0,
+            0,
+            10000,
+            rate,
+            0,
+            0,
+            FRAME_BYTES as u32,
+            0xffff_ffff,
+            0,
+        ];
+        strh.iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+        [0u16, 0, WIDTH as u16, HEIGHT as u16]
+            .iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+        h.extend_from_slice(b"strf");
+        h.extend_from_slice(&40u32.to_le_bytes());
+        let bitmap = [40u32, WIDTH as u32, HEIGHT as u32];
+        bitmap
+            .iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+        h.extend_from_slice(&1u16.to_le_bytes());
+        h.extend_from_slice(&24u16.to_le_bytes());
+        [0u32, FRAME_BYTES as u32, 0, 0, 0, 0]
+            .iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+
+        // Audio stream.
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&92u32.to_le_bytes());
+        h.extend_from_slice(b"strlstrh");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        h.extend_from_slice(b"auds\0\0\0\0");
+        let strh = [0, 0, 0, 2, rate_bytes, 0, 0, rate_bytes, 0xffff_ffff, 2];
+        strh.iter()
+            .for_each(|v| h.extend_from_slice(&v.to_le_bytes()));
+        h.extend_from_slice(&[0; 8]);
+        h.extend_from_slice(b"strf");
+        h.extend_from_slice(&16u32.to_le_bytes());
+        h.extend_from_slice(&wave_format());
+
+        h.extend_from_slice(b"LIST\0\0\0\0movi");
+        write(&mut self.out, &h)?;
+        self.size = h.len() as u64;
+        self.movi_start = self.size - 4;
+        return Ok(());
+    }
+    fn write_chunk(&mut self, id: &'static [u8; 4], data: &[u8]) -> Result<(), String> {
+        let offset = (self.size - self.movi_start) as u32;
+        self.index.push((id, offset, data.len() as u32));
+        write(&mut self.out, id)?;
+        write(&mut self.out, &(data.len() as u32).to_le_bytes())?;
+        write(&mut self.out, data)?;
+        self.size += 8 + data.len() as u64;
+        if data.len() % 2 != 0 {
+            write(&mut self.out, &[0])?;
+            self.size += 1;
+        }
+        return Ok(());
+    }
+    fn patch(&mut self, offset: u64, value: u32) -> Result<(), String> {
+        self.out
+            .seek(SeekFrom::Start(offset))
+            .map_err(|err| err.to_string())?;
+        write(&mut self.out, &value.to_le_bytes())?;
+        self.out
+            .seek(SeekFrom::End(0))
+            .map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+}
+
+fn write(out: &mut impl Write, data: &[u8]) -> Result<(), String> {
+    return out.write_all(data).map_err(|err| err.to_string());
+}
+
+// Mono 16-bit PCM at the output sample rate.
+fn wave_format() -> Vec<u8> {
+    let mut f = Vec::new();
+    f.extend_from_slice(&1u16.to_le_bytes());
+    f.extend_from_slice(&1u16.to_le_bytes());
+    f.extend_from_slice(&(audio::SAMPLE_RATE as u32).to_le_bytes());
+    f.extend_from_slice(&(audio::SAMPLE_RATE as u32 * 2).to_le_bytes());
+    f.extend_from_slice(&2u16.to_le_bytes());
+    f.extend_from_slice(&16u16.to_le_bytes());
+    return f;
+}
+
+fn write_wav_header(out: &mut impl Write, data_bytes: u32) -> Result<(), String> {
+    write(out, b"RIFF")?;
+    write(out, &(36 + data_bytes).to_le_bytes())?;
+    write(out, b"WAVEfmt ")?;
+    write(out, &16u32.to_le_bytes())?;
+    write(out, &wave_format())?;
+    write(out, b"data")?;
+    write(out, &data_bytes.to_le_bytes())?;
+    return Ok(());
+}
+
+// BT.601 studio range.
+fn to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = (66 * r + 129 * g + 25 * b + 128) / 256 + 16;
+    let u = (-38 * r - 74 * g + 112 * b + 128) / 256 + 128;
+    let v = (112 * r - 94 * g - 18 * b + 128) / 256 + 128;
+    return (y as u8, u as u8, v as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    }
+
+    #[test]
+    fn avi_sizes_and_counts() {
+        let path = std::env::temp_dir().join(format!("recorder-test-{}.avi", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut recorder = Recorder::new(&path, 60.0988).unwrap();
+        let rgb = vec![0x40; FRAME_BYTES];
+        recorder.add_frame(&rgb, &[0x1234; 735]).unwrap();
+        recorder.add_frame(&rgb, &[-1; 734]).unwrap();
+        recorder.finish().unwrap();
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&buf[0..4], b"RIFF");
+        assert_eq!(&buf[8..12], b"AVI ");
+        assert_eq!(u32_at(&buf, 4) as usize, buf.len() - 8);
+        // Total frames in avih, then the length of each stream's strh.
+        assert_eq!(&buf[24..28], b"avih");
+        assert_eq!(u32_at(&buf, 48), 2);
+        assert_eq!(&buf[108..112], b"vids");
+        assert_eq!(u32_at(&buf, 140), 2);
+        assert_eq!(&buf[232..236], b"auds");
+        assert_eq!(u32_at(&buf, 264), 735 + 734);
+
+        let movi = buf.windows(4).position(|w| w == b"movi").unwrap();
+        let movi_size = u32_at(&buf, movi - 4) as usize;
+        let chunks = 8 + FRAME_BYTES + 8 + 735 * 2 + 8 + FRAME_BYTES + 8 + 734 * 2;
+        assert_eq!(movi_size, 4 + chunks);
+        let idx1 = movi + movi_size;
+        assert_eq!(&buf[idx1..idx1 + 4], b"idx1");
+        assert_eq!(u32_at(&buf, idx1 + 4), 4 * 16);
+        assert_eq!(idx1 + 8 + 4 * 16, buf.len());
+    }
+}
@@ -8,10 +8,44 @@ pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    SINGLE_SCREEN_LOWER,
+    SINGLE_SCREEN_UPPER,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    DENDY,
+}
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => None,
+        }
+    }
+    pub fn cpu_clock(&self) -> f64 {
+        match self {
+            Region::NTSC => 1789773.0,
+            Region::PAL => 1662607.0,
+            Region::DENDY => 1773448.0,
+        }
+    }
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL => 50.0070,
+            Region::DENDY => 50.0070,
+        }
+    }
 }
 
 pub struct Rom {
     pub rom: Vec<u8>,
+    pub filename: String,
     pub prg_rom_page_count: usize,
     pub chr_rom_page_count: usize,
     pub screen_mirroring: Mirroring,
@@ -19,6 +53,9 @@ pub struct Rom {
     pub trainer_Enable: bool,
     pub four_screen: bool,
     pub mapper_number: u8,
+    pub nes2: bool,
+    pub submapper: u8,
+    pub region: Region,
 
     pub srams: Vec<u8>,
     pub roms: Vec<Vec<u8>>,
@@ -31,6 +68,7 @@ impl Rom {
     pub fn new() -> Self {
         Self {
             rom: (0..1).map(|x| 0).collect(),
+            filename: String::new(),
             prg_rom_page_count: 0,
             chr_rom_page_count: 0,
             screen_mirroring: Mirroring::HORIZONTAL,
@@ -38,6 +76,9 @@ impl Rom {
             trainer_Enable: false,
             four_screen: false,
             mapper_number: 0,
+            nes2: false,
+            submapper: 0,
+            region: Region::NTSC,
             srams: (0..0x2000).map(|x| 0).collect(),
             roms: vec![vec![0; 4]; 4],
             prgrom_state: (0..4).map(|x| 0).collect(),
@@ -97,11 +138,40 @@ impl Rom {
         self.sram_enable = (self.rom[6] & 0x02) != 0;
         self.trainer_Enable = (self.rom[6] & 0x04) != 0;
         self.mapper_number = (self.rom[6] >> 4) | (self.rom[7] & 0xf0) as u8;
+        self.nes2 = (self.rom[7] & 0x0c) == 0x08;
+        self.submapper = if self.nes2 { self.rom[8] >> 4 } else { 0 };
+        self.region = self.detect_region();
 
         let prg_rom_size = self.rom[4] as usize * PRG_ROM_PAGE_SIZE;
         let chr_rom_size = self.rom[5] as usize * CHR_ROM_PAGE_SIZE;
         self.init();
     }
+    // NES 2.0 headers state the timing outright. iNES 1.0 has a PAL bit that
+    // few dumps set, so the usual dump naming tags are checked as well.
+    // There is no checksum database: a PAL game in an iNES 1.0 file with
+    // neither the bit nor a tag runs as NTSC unless --region says otherwise.
+    fn detect_region(&mut self) -> Region {
+        if self.nes2 {
+            return match self.rom[12] & 0x03 {
+                1 => Region::PAL,
+                3 => Region::DENDY,
+                _ => Region::NTSC,
+            };
+        }
+        if (self.rom[9] & 0x01) != 0 {
+            return Region::PAL;
+        }
+        let name = self.filename.to_ascii_lowercase();
+        for tag in ["(e)", "(europe)", "(pal)", "(a)", "(australia)"] {
+            if name.contains(tag) {
+                return Region::PAL;
+            }
+        }
+        if name.contains("(dendy)") {
+            return Region::DENDY;
+        }
+        return Region::NTSC;
+    }
     pub fn clear_roms(&mut self) {
         self.srams.iter().map(|x| 0);
         self.prgrom_state.iter().map(|x| 0);
@@ -0,0 +1,429 @@
+use crate::filter::Filter;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleKind {
+    SCALE,
+    HQ,
+    XBR,
+}
+
+// hq2x output pixels, each given as the neighbourhood order that mirrors it
+// onto the top-left one.
+const HQ2X_MIRRORS: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 1, 0, 5, 4, 3, 8, 7, 6],
+    [6, 7, 8, 3, 4, 5, 0, 1, 2],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+// xBR corner passes in the order they are applied, as rotations taking the
+// bottom-right corner to each of the others.
+const XBR_ROTATIONS: [[isize; 4]; 4] = [[1, 0, 0, 1], [0, 1, -1, 0], [-1, 0, 0, -1], [0, -1, 1, 0]];
+
+// Pixel-art scalers working on the RGB frame.
+//
+// SCALE is AdvMAME Scale2x/Scale3x, HQ is Maxim Stepin's hq2x and XBR is
+// Hyllian's xBR at level 2, the last two comparing colours in YUV.
+pub struct Scaler {
+    kind: ScaleKind,
+    factor: usize,
+    pixels: Vec<u32>,
+    yuv: Vec<(i32, i32, i32)>,
+}
+impl Scaler {
+    pub fn new(kind: ScaleKind, factor: usize) -> Self {
+        Self {
+            kind,
+            factor,
+            pixels: vec![0; 256 * 240],
+            yuv: vec![(0, 0, 0); 256 * 240],
+        }
+    }
+    fn load(&mut self, rgb: &[u8]) {
+        for i in 0..256 * 240 {
+            let (r, g, b) = (rgb[i * 3], rgb[i * 3 + 1], rgb[i * 3 + 2]);
+            self.pixels[i] = ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
+            self.yuv[i] = to_yuv(r as i32, g as i32, b as i32);
+        }
+    }
+    fn index(&self, x: isize, y: isize) -> usize {
+        let x = x.max(0).min(255) as usize;
+        let y = y.max(0).min(239) as usize;
+        return y * 256 + x;
+    }
+    fn pixel(&self, x: isize, y: isize) -> u32 {
+        return self.pixels[self.index(x, y)];
+    }
+    fn scale2x(&self, x: isize, y: isize) -> [u32; 4] {
+        let b = self.pixel(x, y - 1);
+        let d = self.pixel(x - 1, y);
+        let e = self.pixel(x, y);
+        let f = self.pixel(x + 1, y);
+        let h = self.pixel(x, y + 1);
+        if b == h || d == f {
+            return [e; 4];
+        }
+        return [
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ];
+    }
+    fn scale3x(&self, x: isize, y: isize) -> [u32; 9] {
+        let a = self.pixel(x - 1, y - 1);
+        let b = self.pixel(x, y - 1);
+        let c = self.pixel(x + 1, y - 1);
+        let d = self.pixel(x - 1, y);
+        let e = self.pixel(x, y);
+        let f = self.pixel(x + 1, y);
+        let g = self.pixel(x - 1, y + 1);
+        let h = self.pixel(x, y + 1);
+        let i = self.pixel(x + 1, y + 1);
+        if b == h || d == f {
+            return [e; 9];
+        }
+        return [
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) {
+                b
+            } else {
+                e
+            },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) {
+                d
+            } else {
+                e
+            },
+            e,
+            if (b == f && e != i) || (h == f && e != c) {
+                f
+            } else {
+                e
+            },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) {
+                h
+            } else {
+                e
+            },
+            if h == f { f } else { e },
+        ];
+    }
+    fn hq2x(&self, x: isize, y: isize) -> [u32; 4] {
+        let mut w = [0; 9];
+        for (k, p) in w.iter_mut().enumerate() {
+            *p = self.index(x + (k % 3) as isize - 1, y + (k / 3) as isize - 1);
+        }
+        // Every output pixel is worked out as the top-left one, with the
+        // neighbourhood mirrored towards it.
+        let mut block = [0; 4];
+        for (k, mirror) in HQ2X_MIRRORS.iter().enumerate() {
+            block[k] = self.hq2x_pixel(&mirror.map(|p| w[p]));
+        }
+        return block;
+    }
+    // hq2x top-left output pixel. w is the 3x3 neighbourhood, row by row.
+    // Bit n of the pattern is set when the n-th neighbour (centre skipped)
+    // looks different from the centre; the masks below are the hq2x case
+    // table folded down to the conditions that decide this one pixel.
+    fn hq2x_pixel(&self, w: &[usize; 9]) -> u32 {
+        let mut k = 0u8;
+        for (bit, p) in [0, 1, 2, 3, 5, 6, 7, 8].iter().enumerate() {
+            if self.pixels[w[*p]] != self.pixels[w[4]] && self.differs(w[*p], w[4]) {
+                k |= 1 << bit;
+            }
+        }
+        let any = |cases: &[(u8, u8)]| cases.iter().any(|&(m, r)| k & m == r);
+        let wdiff = |a: usize, b: usize| self.differs(w[a], w[b]);
+        let interp = |parts: &[(usize, u32)], shift: u32| {
+            let mut result = 0;
+            for s in [16, 8, 0] {
+                let sum: u32 = parts
+                    .iter()
+                    .map(|&(p, n)| ((self.pixels[w[p]] >> s) & 0xff) * n)
+                    .sum();
+                result |= ((sum >> shift) & 0xff) << s;
+            }
+            return result;
+        };
+
+        if any(&[(0xbf, 0x37), (0xdb, 0x13)]) && wdiff(1, 5) {
+            return interp(&[(4, 3), (3, 1)], 2);
+        }
+        if any(&[(0xdb, 0x49), (0xef, 0x6d)]) && wdiff(7, 3) {
+            return interp(&[(4, 3), (1, 1)], 2);
+        }
+        if any(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && wdiff(3, 1) {
+            return self.pixels[w[4]];
+        }
+        if any(&[
+            (0x6f, 0x2a),
+            (0x5b, 0x0a),
+            (0xbf, 0x3a),
+            (0xdf, 0x5a),
+            (0x9f, 0x8a),
+            (0xcf, 0x8a),
+            (0xef, 0x4e),
+            (0x3f, 0x0e),
+            (0xfb, 0x5a),
+            (0xbb, 0x8a),
+            (0x7f, 0x5a),
+            (0xaf, 0x8a),
+            (0xeb, 0x8a),
+        ]) && wdiff(3, 1)
+        {
+            return interp(&[(4, 3), (0, 1)], 2);
+        }
+        if any(&[(0x0b, 0x08)]) {
+            return interp(&[(4, 2), (0, 1), (1, 1)], 2);
+        }
+        if any(&[(0x0b, 0x02)]) {
+            return interp(&[(4, 2), (0, 1), (3, 1)], 2);
+        }
+        if any(&[(0x2f, 0x2f)]) {
+            return interp(&[(4, 14), (3, 1), (1, 1)], 4);
+        }
+        if any(&[(0xbf, 0x37), (0xdb, 0x13)]) {
+            return interp(&[(4, 5), (1, 2), (3, 1)], 3);
+        }
+        if any(&[(0xdb, 0x49), (0xef, 0x6d)]) {
+            return interp(&[(4, 5), (3, 2), (1, 1)], 3);
+        }
+        if any(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+            return interp(&[(4, 3), (3, 1)], 2);
+        }
+        if any(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+            return interp(&[(4, 3), (1, 1)], 2);
+        }
+        if any(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+            return interp(&[(4, 2), (3, 3), (1, 3)], 3);
+        }
+        if any(&[
+            (0xfb, 0x6a),
+            (0x6f, 0x6e),
+            (0x3f, 0x3e),
+            (0xfb, 0xfa),
+            (0xdf, 0xde),
+            (0xdf, 0x1e),
+        ]) {
+            return interp(&[(4, 3), (0, 1)], 2);
+        }
+        if any(&[
+            (0x0a, 0x00),
+            (0x4f, 0x4b),
+            (0x9f, 0x1b),
+            (0x2f, 0x0b),
+            (0xbe, 0x0a),
+            (0xee, 0x0a),
+            (0x7e, 0x0a),
+            (0xeb, 0x4b),
+            (0x3b, 0x1b),
+        ]) {
+            return interp(&[(4, 2), (3, 1), (1, 1)], 2);
+        }
+        return interp(&[(4, 6), (3, 1), (1, 1)], 3);
+    }
+    // One corner pass of xBR level 2. rot maps an offset in the corner's
+    // frame, where the corner is the bottom-right one, to an offset in the
+    // picture; it is applied to both the neighbourhood and the output block.
+    fn xbr_corner(&self, x: isize, y: isize, rot: [isize; 4], block: &mut [u32]) {
+        let n = self.factor as isize;
+        let at = |i: isize, j: isize| {
+            self.index(x + rot[0] * i + rot[1] * j, y + rot[2] * i + rot[3] * j)
+        };
+        // Output pixel k of the block, counted row by row in the corner's frame.
+        let s = |k: usize| {
+            let i = 2 * (k as isize % n) - (n - 1);
+            let j = 2 * (k as isize / n) - (n - 1);
+            let (i, j) = (rot[0] * i + rot[1] * j, rot[2] * i + rot[3] * j);
+            return ((j + n - 1) / 2 * n + (i + n - 1) / 2) as usize;
+        };
+
+        let (b, c) = (at(0, -1), at(1, -1));
+        let (d, e, f) = (at(-1, 0), at(0, 0), at(1, 0));
+        let (g, h, i) = (at(-1, 1), at(0, 1), at(1, 1));
+        let (f4, i4) = (at(2, 0), at(2, 1));
+        let (h5, i5) = (at(0, 2), at(1, 2));
+        let px = |p: usize| self.pixels[p];
+        let df = |p: usize, q: usize| self.distance(p, q);
+        let eq = |p: usize, q: usize| df(p, q) < 155;
+
+        if px(e) == px(h) || px(e) == px(f) {
+            return;
+        }
+        let across = df(e, c) + df(e, g) + df(i, h5) + df(i, f4) + 4 * df(h, f);
+        let along = df(h, d) + df(h, i5) + df(f, i4) + df(f, b) + 4 * df(e, i);
+        if across > along {
+            return;
+        }
+        let p = if df(e, f) <= df(e, h) { px(f) } else { px(h) };
+        let last = (n * n - 1) as usize;
+        if across == along
+            || !((!eq(f, b) && !eq(h, d))
+                || (eq(e, i) && !eq(f, i4) && !eq(h, i5))
+                || eq(e, g)
+                || eq(e, c))
+        {
+            block[s(last)] = blend(block[s(last)], p, 128);
+            return;
+        }
+
+        let ke = df(f, g);
+        let ki = df(h, c);
+        let left = 2 * ke <= ki && px(e) != px(g) && px(d) != px(g);
+        let up = ke >= 2 * ki && px(e) != px(c) && px(b) != px(c);
+        let mut put = |k: usize, m: i32| {
+            block[s(k)] = if m == 256 {
+                p
+            } else {
+                blend(block[s(k)], p, m)
+            }
+        };
+        match (n, left, up) {
+            (2, true, true) => {
+                put(3, 224);
+                put(2, 64);
+                block[s(1)] = block[s(2)];
+            }
+            (2, true, false) => {
+                put(3, 192);
+                put(2, 64);
+            }
+            (2, false, true) => {
+                put(3, 192);
+                put(1, 64);
+            }
+            (2, false, false) => put(3, 128),
+            (3, true, true) => {
+                put(7, 192);
+                put(6, 64);
+                put(8, 256);
+                block[s(5)] = block[s(7)];
+                block[s(2)] = block[s(6)];
+            }
+            (3, true, false) => {
+                put(7, 192);
+                put(5, 64);
+                put(6, 64);
+                put(8, 256);
+            }
+            (3, false, true) => {
+                put(5, 192);
+                put(7, 64);
+                put(2, 64);
+                put(8, 256);
+            }
+            (3, false, false) => {
+                put(8, 224);
+                put(5, 32);
+                put(7, 32);
+            }
+            (_, true, true) => {
+                put(13, 192);
+                put(12, 64);
+                put(15, 256);
+                put(14, 256);
+                put(11, 256);
+                block[s(10)] = block[s(12)];
+                block[s(3)] = block[s(12)];
+                block[s(7)] = block[s(13)];
+            }
+            (_, true, false) => {
+                put(11, 192);
+                put(13, 192);
+                put(10, 64);
+                put(12, 64);
+                put(14, 256);
+                put(15, 256);
+            }
+            (_, false, true) => {
+                put(14, 192);
+                put(7, 192);
+                put(10, 64);
+                put(3, 64);
+                put(11, 256);
+                put(15, 256);
+            }
+            (_, false, false) => {
+                put(11, 128);
+                put(14, 128);
+                put(15, 256);
+            }
+        }
+    }
+    fn differs(&self, p: usize, q: usize) -> bool {
+        let (y0, u0, v0) = self.yuv[p];
+        let (y1, u1, v1) = self.yuv[q];
+        return (y0 - y1).abs() > 48 || (u0 - u1).abs() > 7 || (v0 - v1).abs() > 6;
+    }
+    fn distance(&self, p: usize, q: usize) -> i32 {
+        let (y0, u0, v0) = self.yuv[p];
+        let (y1, u1, v1) = self.yuv[q];
+        return (y0 - y1).abs() + (u0 - u1).abs() + (v0 - v1).abs();
+    }
+}
+impl Filter for Scaler {
+    fn scale(&self) -> (u32, u32) {
+        return (self.factor as u32, self.factor as u32);
+    }
+    fn apply(&mut self, rgb: &[u8], indices: &[u16], out: &mut Vec<u8>) {
+        self.load(rgb);
+        let n = self.factor;
+        let width = 256 * n;
+        out.resize(width * 240 * n * 3, 0);
+
+        for y in 0..240 {
+            for x in 0..256 {
+                let (sx, sy) = (x as isize, y as isize);
+                match (self.kind, n) {
+                    (ScaleKind::SCALE, 2) => put_block(out, width, x, y, 2, &self.scale2x(sx, sy)),
+                    (ScaleKind::SCALE, _) => put_block(out, width, x, y, 3, &self.scale3x(sx, sy)),
+                    (ScaleKind::HQ, _) => put_block(out, width, x, y, 2, &self.hq2x(sx, sy)),
+                    (ScaleKind::XBR, _) => {
+                        let mut block = [self.pixel(sx, sy); 16];
+                        for rot in XBR_ROTATIONS {
+                            self.xbr_corner(sx, sy, rot, &mut block[..n * n]);
+                        }
+                        put_block(out, width, x, y, n, &block[..n * n]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Writes the n x n block of output pixels for block position (x, y).
+fn put_block(out: &mut [u8], width: usize, x: usize, y: usize, n: usize, block: &[u32]) {
+    for (k, p) in block.iter().enumerate() {
+        let idx = ((y * n + k / n) * width + x * n + k % n) * 3;
+        out[idx] = (p >> 16) as u8;
+        out[idx + 1] = (p >> 8) as u8;
+        out[idx + 2] = *p as u8;
+    }
+}
+
+fn to_yuv(r: i32, g: i32, b: i32) -> (i32, i32, i32) {
+    let y = (r * 299 + g * 587 + b * 114) / 1000;
+    let u = (-r * 169 - g * 331 + b * 500) / 1000 + 128;
+    let v = (r * 500 - g * 419 - b * 81) / 1000 + 128;
+    return (y, u, v);
+}
+
+// Moves a towards b by m/256 in every channel. The halfway blend drops the
+// low bit of both colours first, as xBR does.
+fn blend(a: u32, b: u32, m: i32) -> u32 {
+    let mut result = 0;
+    for shift in [16, 8, 0] {
+        let ca = ((a >> shift) & 0xff) as i32;
+        let cb = ((b >> shift) & 0xff) as i32;
+        let c = if m == 128 {
+            ca / 2 + cb / 2
+        } else {
+            ca + (((cb - ca) * m) >> 8)
+        };
+        result |= (c as u32) << shift;
+    }
+    return result;
+}
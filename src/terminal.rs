@@ -0,0 +1,271 @@
+use crate::config;
+use crate::nes::Key;
+use std::collections::HashMap;
+use std::io::Write;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    ANSI,
+    SIXEL,
+}
+
+type Rgb = (u8, u8, u8);
+
+// Draws frames on the terminal the emulator runs in and reads keys from it
+// in raw mode. ANSI mode puts two pixels in each character cell with the
+// upper half block, scaled down to fit, and only redraws cells that
+// changed. Sixel mode sends the whole picture every frame.
+pub struct Terminal {
+    mode: Mode,
+    original: libc::termios,
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+    size: (usize, usize),
+    cells: Vec<Option<(Rgb, Rgb)>>,
+    input: Vec<u8>,
+}
+impl Terminal {
+    pub fn new(mode: Mode, config: &config::Config) -> Result<Self, String> {
+        let original = unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err("stdin is not a terminal".to_string());
+            }
+            let original = termios;
+            libc::cfmakeraw(&mut termios);
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 0;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            original
+        };
+        let terminal = Self {
+            mode,
+            original,
+            left: config.overscan.left as usize,
+            top: config.overscan.top as usize,
+            width: config.width() as usize,
+            height: config.height() as usize,
+            size: (0, 0),
+            cells: Vec::new(),
+            input: Vec::new(),
+        };
+        terminal.write(b"\x1b[?25l\x1b[2J");
+        return Ok(terminal);
+    }
+    // Keys pressed since the last call. Terminals only report presses, so
+    // holding a button relies on key repeat. Ctrl-C, q and a lone Escape
+    // map to Escape. An escape sequence cut off at the end of the read
+    // stays in `input` for the next call; if nothing followed it by then,
+    // the Escape was a key of its own.
+    pub fn poll_keys(&mut self) -> Vec<Key> {
+        let pending = self.input.len();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut _, 64) };
+            if n <= 0 {
+                break;
+            }
+            self.input.extend_from_slice(&buf[..n as usize]);
+        }
+        let waited = pending > 0 && self.input.len() == pending;
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < self.input.len() {
+            let rest = &self.input[i..];
+            if rest[0] == 0x1b && rest.len() < 3 && !waited {
+                let prefix = rest.len() == 1 || rest[1] == b'[' || rest[1] == b'O';
+                if prefix {
+                    break;
+                }
+            }
+            if rest[0] == 0x1b && rest.len() >= 3 && (rest[1] == b'[' || rest[1] == b'O') {
+                let key = match rest[2] {
+                    b'A' => Some(Key::UP),
+                    b'B' => Some(Key::DOWN),
+                    b'C' => Some(Key::RIGHT),
+                    b'D' => Some(Key::LEFT),
+                    _ => None,
+                };
+                keys.extend(key);
+                i += 3;
+                continue;
+            }
+            let key = match rest[0] {
+                0x03 | 0x1b | b'q' => Some(Key::ESCAPE),
+                b'x' => Some(Key::X),
+                b'z' => Some(Key::Z),
+                b'a' => Some(Key::A),
+                b's' => Some(Key::S),
+                _ => None,
+            };
+            keys.extend(key);
+            i += 1;
+        }
+        self.input.drain(..i);
+        return keys;
+    }
+    pub fn draw(&mut self, rgb: &[u8]) {
+        match self.mode {
+            Mode::ANSI => self.draw_ansi(rgb),
+            Mode::SIXEL => self.draw_sixel(rgb),
+        }
+    }
+    fn pixel(&self, rgb: &[u8], x: usize, y: usize) -> Rgb {
+        let i = ((self.top + y) * 256 + self.left + x) * 3;
+        return (rgb[i], rgb[i + 1], rgb[i + 2]);
+    }
+    // The smallest whole reduction that fits the picture in the window.
+    fn fit(&self) -> usize {
+        let mut size = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe {
+            libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size);
+        }
+        let cols = (size.ws_col as usize).max(1);
+        let rows = (size.ws_row as usize).max(1);
+        let mut scale = 1;
+        while self.width / scale > cols || self.height / scale > rows * 2 {
+            scale += 1;
+        }
+        return scale;
+    }
+    fn average(&self, rgb: &[u8], x: usize, y: usize, scale: usize) -> Rgb {
+        let mut sum = (0, 0, 0);
+        for dy in 0..scale {
+            for dx in 0..scale {
+                let (r, g, b) = self.pixel(rgb, x * scale + dx, y * scale + dy);
+                sum = (sum.0 + r as usize, sum.1 + g as usize, sum.2 + b as usize);
+            }
+        }
+        let n = scale * scale;
+        return ((sum.0 / n) as u8, (sum.1 / n) as u8, (sum.2 / n) as u8);
+    }
+    fn draw_ansi(&mut self, rgb: &[u8]) {
+        let scale = self.fit();
+        let cols = self.width / scale;
+        let lines = self.height / scale;
+        let rows = (lines + 1) / 2;
+        if self.size != (cols, rows) {
+            self.size = (cols, rows);
+            self.cells = vec![None; cols * rows];
+            self.write(b"\x1b[0m\x1b[2J");
+        }
+
+        let mut out = String::new();
+        let mut cursor = None;
+        let mut colors = None;
+        for row in 0..rows {
+            for col in 0..cols {
+                let upper = self.average(rgb, col, row * 2, scale);
+                let lower = if row * 2 + 1 < lines {
+                    self.average(rgb, col, row * 2 + 1, scale)
+                } else {
+                    (0, 0, 0)
+                };
+                let i = row * cols + col;
+                if self.cells[i] == Some((upper, lower)) {
+                    continue;
+                }
+                self.cells[i] = Some((upper, lower));
+                if cursor != Some((row, col)) {
+                    out.push_str(&format!("\x1b[{};{}H", row + 1, col + 1));
+                }
+                if colors != Some((upper, lower)) {
+                    out.push_str(&format!(
+                        "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                        upper.0, upper.1, upper.2, lower.0, lower.1, lower.2
+                    ));
+                    colors = Some((upper, lower));
+                }
+                out.push('\u{2580}');
+                cursor = Some((row, col + 1));
+            }
+        }
+        self.write(out.as_bytes());
+    }
+    fn draw_sixel(&mut self, rgb: &[u8]) {
+        // Colour registers are handed out per frame; past 256 colours the
+        // rest share the last register.
+        let mut registers: HashMap<Rgb, usize> = HashMap::new();
+        let mut map = vec![0; self.width * self.height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = self.pixel(rgb, x, y);
+                let next = registers.len().min(255);
+                map[y * self.width + x] = *registers.entry(color).or_insert(next);
+            }
+        }
+
+        let mut out = format!("\x1b[H\x1bP0;1;0q\"1;1;{};{}", self.width, self.height);
+        let mut palette: Vec<(&Rgb, &usize)> = registers.iter().collect();
+        palette.sort_by_key(|&(_, register)| *register);
+        for ((r, g, b), register) in palette {
+            out.push_str(&format!(
+                "#{};2;{};{};{}",
+                register,
+                *r as usize * 100 / 255,
+                *g as usize * 100 / 255,
+                *b as usize * 100 / 255
+            ));
+        }
+        for band in (0..self.height).step_by(6) {
+            let band_lines = (self.height - band).min(6);
+            let mut used: Vec<usize> = (0..band_lines)
+                .flat_map(|dy| map[(band + dy) * self.width..(band + dy + 1) * self.width].iter())
+                .copied()
+                .collect();
+            used.sort_unstable();
+            used.dedup();
+            for register in used {
+                out.push_str(&format!("#{}", register));
+                let mut run = (0, 0);
+                for x in 0..self.width {
+                    let mut bits = 0;
+                    for dy in 0..band_lines {
+                        if map[(band + dy) * self.width + x] == register {
+                            bits |= 1 << dy;
+                        }
+                    }
+                    let c = (63 + bits) as u8;
+                    if run.1 > 0 && run.0 != c {
+                        push_run(&mut out, run);
+                        run.1 = 0;
+                    }
+                    run = (c, run.1 + 1);
+                }
+                push_run(&mut out, run);
+                out.push('$');
+            }
+            out.push('-');
+        }
+        out.push_str("\x1b\\");
+        self.write(out.as_bytes());
+    }
+    fn write(&self, data: &[u8]) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(data);
+        let _ = stdout.flush();
+    }
+}
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.write(b"\x1b[0m\x1b[?25h\r\n");
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+fn push_run(out: &mut String, (c, count): (u8, usize)) {
+    if count > 3 {
+        out.push_str(&format!("!{}{}", count, c as char));
+    } else {
+        (0..count).for_each(|_| out.push(c as char));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::image;
+use crate::ppu;
+
+// One picture of the PPU's memory:
+//
+//   +-------------------+------------------+
+//   |                   | pattern tables   |  256x128, palette `palette`
+//   |  nametables 0-3   +------------------+
+//   |  512x480, scroll  | OAM 8x8 cells    |  128x128
+//   |  rectangle drawn  +------------------+
+//   |                   | palette RAM      |  256x32
+//   +-------------------+------------------+
+pub const WIDTH: usize = 768;
+pub const HEIGHT: usize = 480;
+
+const PATTERN_Y: usize = 0;
+const OAM_Y: usize = 136;
+const PALETTE_Y: usize = 272;
+const SCROLL_COLOR: (u8, u8, u8) = (0xff, 0x00, 0xff);
+const EMPTY_COLOR: (u8, u8, u8) = (0x40, 0x40, 0x40);
+
+pub struct Viewer {
+    pub palette: usize,
+    pub pixels: Vec<u8>,
+}
+impl Viewer {
+    pub fn new() -> Self {
+        Self {
+            palette: 0,
+            pixels: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+    pub fn next_palette(&mut self) {
+        self.palette = (self.palette + 1) & 0x07;
+    }
+    pub fn render(&mut self, ppu: &mut ppu::Ppu) {
+        self.pixels.iter_mut().for_each(|p| *p = 0);
+        self.draw_nametables(ppu);
+        self.draw_scroll(ppu);
+        self.draw_patterns(ppu);
+        self.draw_oam(ppu);
+        self.draw_palette(ppu);
+    }
+    pub fn dump_png(&mut self, ppu: &mut ppu::Ppu, path: &str) -> Result<(), String> {
+        self.render(ppu);
+        return image::write_png(path, WIDTH as u32, HEIGHT as u32, &self.pixels);
+    }
+    fn put(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let i = (y * WIDTH + x) * 3;
+        self.pixels[i] = rgb.0;
+        self.pixels[i + 1] = rgb.1;
+        self.pixels[i + 2] = rgb.2;
+    }
+    // Colour of a 2-bit pattern pixel in one of the eight palettes.
+    fn color(ppu: &mut ppu::Ppu, palette: usize, pixel: u8) -> (u8, u8, u8) {
+        let addr = if pixel == 0 {
+            0x3f00
+        } else {
+            0x3f00 + palette * 4 + pixel as usize
+        };
+        let color = ppu.peek(addr);
+        return ppu.rgb(color);
+    }
+    fn draw_tile_row(
+        &mut self,
+        ppu: &mut ppu::Ppu,
+        addr: usize,
+        x: usize,
+        y: usize,
+        palette: usize,
+        flip: bool,
+        transparent: bool,
+    ) {
+        let low = ppu.peek(addr);
+        let high = ppu.peek(addr + 8);
+        for i in 0..8 {
+            let bit = if flip { i } else { 7 - i };
+            let pixel = ((low >> bit) & 0x01) | (((high >> bit) & 0x01) << 1);
+            if transparent && pixel == 0 {
+                continue;
+            }
+            let rgb = Viewer::color(ppu, palette, pixel);
+            self.put(x + i, y, rgb);
+        }
+    }
+    fn draw_nametables(&mut self, ppu: &mut ppu::Ppu) {
+        for y in 0..480 {
+            for x in 0..512 {
+                let color = ppu.peek_background(x, y);
+                let rgb = ppu.rgb(color);
+                self.put(x, y, rgb);
+            }
+        }
+    }
+    // The visible 256x240 window, wrapping around the nametable space.
+    fn draw_scroll(&mut self, ppu: &mut ppu::Ppu) {
+        let (sx, sy) = ppu.scroll_origin();
+        for i in 0..256 {
+            self.put((sx + i) % 512, sy % 480, SCROLL_COLOR);
+            self.put((sx + i) % 512, (sy + 239) % 480, SCROLL_COLOR);
+        }
+        for i in 0..240 {
+            self.put(sx % 512, (sy + i) % 480, SCROLL_COLOR);
+            self.put((sx + 255) % 512, (sy + i) % 480, SCROLL_COLOR);
+        }
+    }
+    fn draw_patterns(&mut self, ppu: &mut ppu::Ppu) {
+        for table in 0..2 {
+            for tile in 0..256 {
+                let x = 512 + table * 128 + (tile & 0x0f) * 8;
+                let y = PATTERN_Y + (tile >> 4) * 8;
+                for row in 0..8 {
+                    let addr = table * 0x1000 + tile * 16 + row;
+                    self.draw_tile_row(ppu, addr, x, y + row, self.palette, false, false);
+                }
+            }
+        }
+    }
+    fn draw_oam(&mut self, ppu: &mut ppu::Ppu) {
+        let ctrl = ppu.ctrl();
+        let height = if (ctrl & 0x20) != 0 { 16 } else { 8 };
+        for sprite in 0..64 {
+            let x = 512 + (sprite & 0x07) * 16;
+            let y = OAM_Y + (sprite >> 3) * 16;
+            for cy in 0..16 {
+                for cx in 0..16 {
+                    self.put(x + cx, y + cy, EMPTY_COLOR);
+                }
+            }
+            let tile = ppu.sprite_ram[sprite * 4 + 1] as usize;
+            let attr = ppu.sprite_ram[sprite * 4 + 2];
+            let base = if height == 16 {
+                ((tile & 0x01) << 12) + (tile & 0xfe) * 16
+            } else {
+                (((ctrl & 0x08) as usize) << 9) + tile * 16
+            };
+            let top = y + (16 - height) / 2;
+            for row in 0..height {
+                let src = if (attr & 0x80) != 0 {
+                    height - 1 - row
+                } else {
+                    row
+                };
+                let addr = base + (src & 0x08) * 2 + (src & 0x07);
+                let palette = 4 + (attr & 0x03) as usize;
+                let flip = (attr & 0x40) != 0;
+                self.draw_tile_row(ppu, addr, x + 4, top + row, palette, flip, true);
+            }
+        }
+    }
+    fn draw_palette(&mut self, ppu: &mut ppu::Ppu) {
+        for entry in 0..32 {
+            let color = ppu.peek(0x3f00 + entry);
+            let rgb = ppu.rgb(color);
+            let x = 512 + (entry & 0x0f) * 16;
+            let y = PALETTE_Y + (entry >> 4) * 16;
+            for cy in 0..16 {
+                for cx in 0..16 {
+                    self.put(x + cx, y + cy, rgb);
+                }
+            }
+        }
+    }
+}